@@ -0,0 +1,271 @@
+//! Fitting of round-trip samples to common latency distributions.
+//!
+//! Each candidate is fitted by maximum likelihood and scored with the
+//! Kolmogorov-Smirnov statistic, the largest distance between the fitted
+//! and the empirical CDF.
+
+use std::f64::consts::SQRT_2;
+
+struct Fit {
+    name: &'static str,
+    params: String,
+    ks: f64,
+}
+
+/// Error function, Abramowitz and Stegun formula 7.1.26 (|error| < 1.5e-7).
+fn erf(x: f64) -> f64 {
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    sign * (1.0 - poly * (-x * x).exp())
+}
+
+/// Kolmogorov-Smirnov statistic of sorted `samples` against `cdf`.
+fn ks_statistic(sorted: &[f64], cdf: impl Fn(f64) -> f64) -> f64 {
+    let n = sorted.len() as f64;
+    sorted
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let f = cdf(x);
+            (f - i as f64 / n).max((i + 1) as f64 / n - f)
+        })
+        .fold(0.0, f64::max)
+}
+
+fn exponential_mean(sorted: &[f64]) -> f64 {
+    sorted.iter().sum::<f64>() / sorted.len() as f64
+}
+
+fn fit_exponential(sorted: &[f64]) -> Fit {
+    let mean = exponential_mean(sorted);
+    Fit {
+        name: "exponential",
+        params: format!("mean={:.3} us", mean),
+        ks: ks_statistic(sorted, |x| 1.0 - (-x / mean).exp()),
+    }
+}
+
+/// Maximum likelihood `(mu, sigma)` of the log of the samples.
+fn log_normal_params(sorted: &[f64]) -> (f64, f64) {
+    let n = sorted.len() as f64;
+    let mu = sorted.iter().map(|x| x.ln()).sum::<f64>() / n;
+    let sigma = (sorted.iter().map(|x| (x.ln() - mu).powi(2)).sum::<f64>() / n).sqrt();
+    (mu, sigma)
+}
+
+fn fit_log_normal(sorted: &[f64]) -> Fit {
+    let (mu, sigma) = log_normal_params(sorted);
+    Fit {
+        name: "log-normal",
+        params: format!("mu={:.4} sigma={:.4}", mu, sigma),
+        ks: ks_statistic(sorted, |x| {
+            0.5 * (1.0 + erf((x.ln() - mu) / (sigma * SQRT_2)))
+        }),
+    }
+}
+
+/// Maximum likelihood `(shape, scale)` of a Weibull distribution.
+fn weibull_params(sorted: &[f64]) -> (f64, f64) {
+    let n = sorted.len() as f64;
+    // The shape estimate is scale invariant, so normalize by the maximum to
+    // keep x^k from overflowing.
+    let max = sorted[sorted.len() - 1];
+    let scaled: Vec<f64> = sorted.iter().map(|x| x / max).collect();
+    let mean_ln = scaled.iter().map(|y| y.ln()).sum::<f64>() / n;
+    // The likelihood equation for the shape k is monotonically increasing in
+    // k, so bisect it on a log scale.
+    let likelihood = |k: f64| {
+        let (sum_pow, sum_pow_ln) = scaled.iter().fold((0.0, 0.0), |(a, b), &y| {
+            let p = y.powf(k);
+            (a + p, b + p * y.ln())
+        });
+        sum_pow_ln / sum_pow - 1.0 / k - mean_ln
+    };
+    let (mut lo, mut hi) = (1e-3f64, 1e3f64);
+    for _ in 0..100 {
+        let mid = (lo * hi).sqrt();
+        if likelihood(mid) < 0.0 {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let shape = (lo * hi).sqrt();
+    let scale = max * (scaled.iter().map(|y| y.powf(shape)).sum::<f64>() / n).powf(1.0 / shape);
+    (shape, scale)
+}
+
+fn fit_weibull(sorted: &[f64]) -> Fit {
+    let (shape, scale) = weibull_params(sorted);
+    Fit {
+        name: "weibull",
+        params: format!("shape={:.4} scale={:.3} us", shape, scale),
+        ks: ks_statistic(sorted, |x| 1.0 - (-(x / scale).powf(shape)).exp()),
+    }
+}
+
+/// Fits the positive `samples` to each candidate, from best to worst fit, or
+/// returns `None` if there are fewer than two of them.
+fn fit_all(samples: &[f64]) -> Option<[Fit; 3]> {
+    let mut sorted: Vec<f64> = samples.iter().copied().filter(|&x| x > 0.0).collect();
+    if sorted.len() < 2 {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut fits = [
+        fit_exponential(&sorted),
+        fit_log_normal(&sorted),
+        fit_weibull(&sorted),
+    ];
+    fits.sort_by(|a, b| a.ks.partial_cmp(&b.ks).unwrap());
+    Some(fits)
+}
+
+/// Fits the round-trip `samples` (in microseconds) and prints the candidate
+/// distributions from best to worst fit.
+pub fn print_fits(samples: &[f64]) {
+    let fits = match fit_all(samples) {
+        Some(fits) => fits,
+        None => {
+            println!("not enough samples to fit a distribution");
+            return;
+        }
+    };
+
+    println!("distribution fits (Kolmogorov-Smirnov D, lower is better):");
+    for (i, fit) in fits.iter().enumerate() {
+        println!(
+            "  {:<12} D={:.4}  {}{}",
+            fit.name,
+            fit.ks,
+            fit.params,
+            if i == 0 { "  (best)" } else { "" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// `n` samples at the midpoint quantiles of the distribution with the
+    /// inverse cdf `quantile`, sorted.
+    fn quantiles(n: usize, quantile: impl Fn(f64) -> f64) -> Vec<f64> {
+        (0..n)
+            .map(|i| quantile((i as f64 + 0.5) / n as f64))
+            .collect()
+    }
+
+    fn exponential(mean: f64) -> Vec<f64> {
+        quantiles(10000, |u| -mean * (1.0 - u).ln())
+    }
+
+    fn weibull(shape: f64, scale: f64) -> Vec<f64> {
+        quantiles(10000, |u| scale * (-(1.0 - u).ln()).powf(1.0 / shape))
+    }
+
+    /// Log-normal samples from a seeded Box-Muller transform, sorted.
+    fn log_normal(mu: f64, sigma: f64) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(217);
+        let mut samples: Vec<f64> = (0..10000)
+            .map(|_| {
+                let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mu + sigma * z).exp()
+            })
+            .collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        samples
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    fn best_fit(samples: &[f64]) -> &'static str {
+        fit_all(samples).unwrap()[0].name
+    }
+
+    #[test]
+    fn erf_matches_reference_values() {
+        // Reference values to 10 digits, the approximation is good to 1.5e-7.
+        for (x, expected) in [
+            (0.0, 0.0),
+            (0.1, 0.1124629160),
+            (0.5, 0.5204998778),
+            (1.0, 0.8427007929),
+            (2.0, 0.9953222650),
+            (3.0, 0.9999779095),
+        ] {
+            assert_close(erf(x), expected, 1.5e-7);
+            assert_close(erf(-x), -expected, 1.5e-7);
+        }
+        assert_eq!(erf(10.0), 1.0);
+    }
+
+    #[test]
+    fn ks_statistic_is_the_largest_cdf_distance() {
+        let uniform = |x: f64| (x / 4.0).clamp(0.0, 1.0);
+        // Midpoint quantiles are off by half a step everywhere.
+        assert_close(ks_statistic(&[0.5, 1.5, 2.5, 3.5], uniform), 0.125, 1e-12);
+        assert_close(ks_statistic(&[1.0, 2.0, 3.0, 4.0], uniform), 0.25, 1e-12);
+        // All samples below where the cdf starts to rise.
+        assert_close(ks_statistic(&[1.0, 2.0], |_| 0.0), 1.0, 1e-12);
+    }
+
+    #[test]
+    fn exponential_fit_recovers_the_mean() {
+        assert_close(exponential_mean(&exponential(50.0)), 50.0, 0.5);
+        assert!(fit_exponential(&exponential(50.0)).ks < 0.01);
+    }
+
+    #[test]
+    fn log_normal_fit_recovers_mu_and_sigma() {
+        let (mu, sigma) = log_normal_params(&log_normal(4.0, 0.5));
+        assert_close(mu, 4.0, 0.02);
+        assert_close(sigma, 0.5, 0.02);
+    }
+
+    #[test]
+    fn weibull_bisection_recovers_shape_and_scale() {
+        for shape in [0.5, 1.0, 2.0, 5.0] {
+            let (fitted_shape, fitted_scale) = weibull_params(&weibull(shape, 100.0));
+            assert_close(fitted_shape, shape, shape * 0.02);
+            assert_close(fitted_scale, 100.0, 2.0);
+        }
+        // Normalizing by the maximum keeps large samples from overflowing.
+        let (shape, scale) = weibull_params(&weibull(20.0, 1e9));
+        assert_close(shape, 20.0, 0.4);
+        assert_close(scale, 1e9, 2e7);
+    }
+
+    #[test]
+    fn the_generating_distribution_fits_best() {
+        assert_eq!(best_fit(&log_normal(4.0, 0.5)), "log-normal");
+        assert_eq!(best_fit(&weibull(2.0, 100.0)), "weibull");
+        // An exponential is a weibull of shape 1, so only the log-normal is
+        // guaranteed to do worse.
+        let fits = fit_all(&exponential(50.0)).unwrap();
+        assert_eq!(fits[2].name, "log-normal");
+    }
+
+    #[test]
+    fn too_few_positive_samples_are_not_fitted() {
+        assert!(fit_all(&[]).is_none());
+        assert!(fit_all(&[0.0, 0.0, 5.0]).is_none());
+        assert!(fit_all(&[1.0, 2.0]).is_some());
+    }
+}
@@ -1,3 +1,75 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{run_client_loop, ClientError, ClientOpts, Echo};
+use latency_client::LatencyClient;
+use latency_server::{Latency, LatencyServer};
+
 tonic::include_proto!("latency");
 
+/// Echoes every message back unchanged.
 struct Server;
+
+#[tonic::async_trait]
+impl Latency for Server {
+    type BidirectionalStream = Streaming<Message>;
+
+    async fn bidirectional(
+        &self,
+        request: Request<Streaming<Message>>,
+    ) -> Result<Response<Self::BidirectionalStream>, Status> {
+        Ok(Response::new(request.into_inner()))
+    }
+
+    async fn unary(&self, request: Request<Message>) -> Result<Response<Message>, Status> {
+        Ok(Response::new(request.into_inner()))
+    }
+}
+
+pub fn start_grpc_server(addr: SocketAddr) {
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(LatencyServer::new(Server))
+                .serve(addr),
+        )
+        .unwrap();
+}
+
+/// Times unary `Unary` calls, which echo their payload, the same way the tcp
+/// client times its round trips.
+pub fn start_grpc_client(addr: SocketAddr, client_opts: ClientOpts) -> Result<(), ClientError> {
+    // A single-threaded runtime keeps the calls on the measuring thread.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut client = runtime
+        .block_on(LatencyClient::connect(format!("http://{}", addr)))
+        .map_err(|e| ClientError::Connect {
+            target: addr.to_string(),
+            source: io::Error::other(e),
+        })?;
+
+    let client_run = run_client_loop(
+        &client_opts,
+        Some(addr.to_string()),
+        None,
+        None,
+        |_, data| {
+            let start = Instant::now();
+            let reply = runtime
+                .block_on(client.unary(Message {
+                    payload: data.to_vec(),
+                }))
+                .map_err(|status| io::Error::other(status.to_string()))?
+                .into_inner();
+            Ok(Some((start.elapsed(), Echo::compare(data, &reply.payload))))
+        },
+    );
+    client_run.result
+}
@@ -1,12 +1,33 @@
-use std::io::{Read, Write};
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
-use rand::RngCore;
+use clap::{Args, Parser};
+use rand::{Rng, RngCore};
 
+use report::{OutputFormat, Reporter, RunParams};
+use socket::{ConnectOptions, PortRange};
+
+mod fit;
 mod grpc;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod report;
+mod socket;
+mod trend;
+#[cfg(feature = "tui")]
+mod tui;
+mod websocket;
 
 #[derive(Parser, Debug)]
 pub enum Opts {
@@ -26,6 +47,21 @@ pub enum Opts {
             about = "maximum size of data allowed to receive"
         )]
         max_data_size: usize,
+        #[clap(long, about = "allow --max-data-size above 64 MiB")]
+        allow_large: bool,
+        #[clap(
+            long,
+            default_value = "true",
+            parse(try_from_str),
+            about = "set TCP_NODELAY on every tcp stream, pass false to keep Nagle's algorithm"
+        )]
+        nodelay: bool,
+        #[clap(
+            validator = at_least_one,
+            long,
+            about = "give up connecting to the remote after this many milliseconds, and close clients idle that long"
+        )]
+        timeout: Option<u64>,
     },
     #[clap(about = "start a network latency test udp forwarder")]
     UdpForwarder {
@@ -43,6 +79,13 @@ pub enum Opts {
             about = "maximum size of data allowed to receive"
         )]
         max_data_size: usize,
+        #[clap(
+            validator = at_least_one,
+            long,
+            default_value = "60",
+            about = "close the upstream socket of a client that sent nothing for this many seconds"
+        )]
+        idle_timeout: u64,
     },
     #[clap(about = "start a network latency tcp tester")]
     TcpTester {
@@ -62,6 +105,43 @@ pub enum Opts {
             about = "the number of repetitions"
         )]
         repeat: usize,
+        #[clap(
+            short,
+            long,
+            about = "print one line per round trip before the summary"
+        )]
+        verbose: bool,
+        #[clap(
+            long,
+            default_value = "human",
+            about = "print results as human, csv or json"
+        )]
+        output_format: OutputFormat,
+        #[clap(
+            long,
+            about = "print a histogram of the round trips in power-of-two buckets to stderr"
+        )]
+        histogram: bool,
+        #[clap(
+            long,
+            default_value = "0",
+            about = "run this many untimed round trips before the measured ones"
+        )]
+        warmup: usize,
+        #[clap(
+            validator = at_least_one,
+            long,
+            default_value = "1",
+            about = "cycle through this many random payloads generated before the run"
+        )]
+        unique_payloads: usize,
+        #[clap(
+            long,
+            default_value = "true",
+            parse(try_from_str),
+            about = "set TCP_NODELAY on every tcp stream, pass false to keep Nagle's algorithm"
+        )]
+        nodelay: bool,
     },
 
     #[clap(about = "start a network latency test tcp server")]
@@ -78,6 +158,26 @@ pub enum Opts {
             about = "maximum size of data allowed to receive"
         )]
         max_data_size: usize,
+        #[clap(long, about = "allow --max-data-size above 64 MiB")]
+        allow_large: bool,
+        #[clap(
+            long,
+            about = "echo in chunks of <bytes>, sleeping <ms> between chunks (format: <bytes>:<ms>)"
+        )]
+        drip: Option<Drip>,
+        #[clap(
+            long,
+            default_value = "true",
+            parse(try_from_str),
+            about = "set TCP_NODELAY on every tcp stream, pass false to keep Nagle's algorithm"
+        )]
+        nodelay: bool,
+        #[clap(
+            validator = at_least_one,
+            long,
+            about = "close connections idle for this many milliseconds, or whose writes stall that long"
+        )]
+        timeout: Option<u64>,
     },
     #[clap(about = "start a network latency test udp server")]
     UdpServer {
@@ -96,66 +196,556 @@ pub enum Opts {
     },
     #[clap(about = "start as a tcp worker")]
     TcpClient {
-        #[clap(about = "the remote socket address to connect")]
-        socket_addr: SocketAddr,
-        #[clap(short, long, default_value = "1024", about = "the data size to send")]
-        data_size: usize,
         #[clap(
-            short,
+            required_unless_present = "fd",
+            about = "the remote socket address to connect"
+        )]
+        socket_addr: Option<SocketAddr>,
+        #[clap(
             long,
-            default_value = "1000",
-            about = "the number of repetitions"
+            conflicts_with_all = &["socket-addr", "bind", "mss", "vrf", "priority", "source-port-range"],
+            about = "run over an already connected tcp socket inherited as this file descriptor"
         )]
-        repeat: usize,
+        fd: Option<i32>,
+        #[clap(
+            long,
+            conflicts_with = "source-port-range",
+            about = "bind this local socket address before connecting"
+        )]
+        bind: Option<SocketAddr>,
+        #[clap(flatten)]
+        client_opts: ClientOpts,
+        #[clap(
+            long,
+            about = "report the distribution of gaps between consecutive reads of a response"
+        )]
+        read_gaps: bool,
+        #[clap(
+            long,
+            about = "report the time spent writing the request and reading the response separately"
+        )]
+        split_timing: bool,
+        #[clap(
+            long,
+            about = "set TCP_MAXSEG to this many bytes before connecting (Linux only)"
+        )]
+        mss: Option<u32>,
+        #[clap(
+            long,
+            about = "bind the socket to this vrf device before connecting (Linux only, requires CAP_NET_RAW)"
+        )]
+        vrf: Option<String>,
+        #[clap(
+            long,
+            about = "set SO_PRIORITY on the socket to select a qdisc band (Linux only)"
+        )]
+        priority: Option<u32>,
+        #[clap(
+            long,
+            about = "bind to the first free local port in this range before connecting (format: <start>:<end>)"
+        )]
+        source_port_range: Option<PortRange>,
+        #[clap(long, validator = positive, about = "pace outgoing bytes to the given bandwidth in Mbit/s")]
+        shape_mbps: Option<f64>,
+        #[clap(
+            validator = valid_rate,
+            long,
+            value_name = "REQUESTS_PER_SEC",
+            about = "send the round trips on a fixed schedule of this many per second instead of back to back"
+        )]
+        rate: Option<u64>,
+        #[clap(
+            long,
+            default_value = "true",
+            parse(try_from_str),
+            about = "set TCP_NODELAY on every tcp stream, pass false to keep Nagle's algorithm"
+        )]
+        nodelay: bool,
+        #[clap(
+            validator = at_least_one,
+            long,
+            about = "give up connecting, reading or writing after this many milliseconds"
+        )]
+        timeout: Option<u64>,
+        #[clap(
+            validator = at_least_one,
+            long,
+            default_value = "1",
+            conflicts_with_all = &["fd", "read-gaps", "split-timing", "shape-mbps", "rate", "stress-cpu", "cpu-quota", "print-every", "statsd"],
+            about = "run the round trips over this many concurrent connections and merge their samples"
+        )]
+        #[cfg_attr(feature = "tui", clap(conflicts_with = "tui"))]
+        #[cfg_attr(feature = "otlp", clap(conflicts_with = "otlp"))]
+        connections: usize,
+        #[clap(
+            long,
+            value_name = "MICROS",
+            validator = at_least_one,
+            conflicts_with_all = &["fd", "read-gaps", "split-timing", "shape-mbps", "rate", "stress-cpu", "cpu-quota", "print-every", "statsd", "duration"],
+            about = "after a lock-step baseline of --repeat round trips on each of the --connections, repeat them with a random pause of up to this many microseconds before each and compare"
+        )]
+        #[cfg_attr(feature = "tui", clap(conflicts_with = "tui"))]
+        #[cfg_attr(feature = "otlp", clap(conflicts_with = "otlp"))]
+        stagger_jitter: Option<u64>,
     },
     #[clap(about = "start as a udp worker")]
     UdpClient {
         #[clap(
-            default_value = "127.0.0.1:9999",
-            about = "the local socket address to connect"
+            required_unless_present = "fd",
+            about = "the remote socket address to connect"
         )]
-        local_addr: SocketAddr,
-        #[clap(short, long, default_value = "1024", about = "the data size to send")]
-        data_size: usize,
+        socket_addr: Option<SocketAddr>,
         #[clap(
-            short,
+            long,
+            about = "the local socket address to bind [default: an ephemeral port on any address]"
+        )]
+        bind: Option<SocketAddr>,
+        #[clap(
+            long,
+            conflicts_with_all = &["socket-addr", "bind"],
+            about = "run over an already connected udp socket inherited as this file descriptor"
+        )]
+        fd: Option<i32>,
+        #[clap(
+            validator = at_least_one,
             long,
             default_value = "1000",
-            about = "the number of repetitions"
+            about = "count a datagram as lost if its echo takes longer than this many milliseconds"
         )]
-        repeat: usize,
+        recv_timeout: u64,
+        #[clap(
+            long,
+            about = "disable udp generic segmentation offload on the socket (Linux only, NIC offloads need ethtool)"
+        )]
+        no_gso: bool,
+        #[clap(
+            long,
+            about = "disable udp generic receive offload on the socket (Linux only, NIC offloads need ethtool)"
+        )]
+        no_gro: bool,
+        #[clap(
+            validator = valid_rate,
+            long,
+            value_name = "REQUESTS_PER_SEC",
+            about = "send the datagrams on a fixed schedule of this many per second instead of back to back"
+        )]
+        rate: Option<u64>,
+        #[clap(flatten)]
+        client_opts: ClientOpts,
+    },
+    #[clap(about = "start a network latency test grpc server")]
+    GrpcServer {
+        #[clap(
+            default_value = "127.0.0.1:8888",
+            about = "the local socket address to listen"
+        )]
+        socket_addr: SocketAddr,
+    },
+    #[clap(about = "start as a grpc worker")]
+    GrpcClient {
+        #[clap(about = "the remote socket address to connect")]
+        socket_addr: SocketAddr,
+        #[clap(flatten)]
+        client_opts: ClientOpts,
+    },
+    #[clap(about = "start as a websocket worker")]
+    WebSocketClient {
+        #[clap(about = "the websocket url to connect, e.g. ws://127.0.0.1:8080/")]
+        url: websocket::Url,
+        #[clap(flatten)]
+        client_opts: ClientOpts,
+    },
+    #[clap(
+        about = "echo over an in-process channel instead of a socket to measure the harness overhead"
+    )]
+    Inproc {
+        #[clap(flatten)]
+        client_opts: ClientOpts,
+    },
+    #[clap(about = "stress test tcp connection establishment")]
+    ConnStress {
+        #[clap(about = "the remote socket address to connect")]
+        socket_addr: SocketAddr,
+        #[clap(
+            short,
+            long,
+            default_value = "10",
+            about = "the test duration in seconds"
+        )]
+        duration: u64,
+        #[clap(
+            validator = at_least_one,
+            short,
+            long,
+            default_value = "1",
+            about = "the number of threads opening connections concurrently"
+        )]
+        concurrency: usize,
+    },
+    #[clap(about = "report how a statistic of the runs recorded with --append-summary changed")]
+    Trend {
+        #[clap(about = "the file written by --append-summary")]
+        file: PathBuf,
+        #[clap(
+            long,
+            default_value = "p99",
+            about = "the statistic to follow: min, max, mean, stddev, p50, p90, p99 or p99.9"
+        )]
+        statistic: trend::Statistic,
+        #[clap(long, about = "only include the runs against this target")]
+        target: Option<String>,
     },
 }
 
-fn start_tcp_forwarder(remote_addr: SocketAddr, local_addr: SocketAddr, max_data_size: usize) {
+/// Options shared by the tcp and udp clients.
+#[derive(Args, Debug)]
+pub struct ClientOpts {
+    #[clap(short, long, default_value = "1024", about = "the data size to send")]
+    data_size: usize,
+    #[clap(
+        short,
+        long,
+        default_value = "1000",
+        about = "the number of repetitions"
+    )]
+    repeat: usize,
+    #[clap(
+        long,
+        conflicts_with_all = &["repeat", "stress-cpu"],
+        about = "keep sending round trips for this many seconds instead of --repeat times"
+    )]
+    duration: Option<u64>,
+    #[clap(
+        validator = at_least_one,
+        long,
+        default_value = "1000000",
+        about = "with --duration, keep a random sample of at most this many round trips for the statistics"
+    )]
+    max_samples: usize,
+    #[clap(
+        long,
+        default_value = "0",
+        about = "run this many untimed round trips before the measured ones"
+    )]
+    warmup: usize,
+    #[clap(
+        validator = at_least_one,
+        long,
+        default_value = "1",
+        about = "cycle through this many random payloads generated before the run"
+    )]
+    unique_payloads: usize,
+    #[clap(
+        long,
+        about = "compare the round trips of first-seen and repeated --unique-payloads (turns off the udp client's sequence numbers)"
+    )]
+    payload_repeat: bool,
+    #[clap(
+        long,
+        about = "emit each round trip as a statsd timing metric to this address"
+    )]
+    statsd: Option<SocketAddr>,
+    #[clap(
+        long,
+        default_value = "network_latency.rtt",
+        about = "the statsd metric name"
+    )]
+    statsd_metric: String,
+    #[cfg(feature = "otlp")]
+    #[clap(
+        long,
+        about = "export each round trip as an OpenTelemetry span to this OTLP/HTTP endpoint, e.g. http://127.0.0.1:4318"
+    )]
+    otlp: Option<otlp::Endpoint>,
+    #[clap(long, about = "report the achieved operations per second")]
+    ops_report: bool,
+    #[clap(
+        long,
+        about = "fit the samples to exponential, log-normal and weibull distributions"
+    )]
+    fit: bool,
+    #[clap(
+        validator = valid_percentage,
+        long,
+        about = "sleep between round trips to keep the measuring thread under this cpu usage in percent"
+    )]
+    cpu_quota: Option<f64>,
+    #[clap(
+        short,
+        long,
+        about = "print one line per round trip before the summary"
+    )]
+    verbose: bool,
+    #[clap(
+        long,
+        default_value = "human",
+        about = "print results as human, csv or json; csv and json carry only the samples and summary"
+    )]
+    output_format: OutputFormat,
+    #[clap(
+        long,
+        about = "print a histogram of the round trips in power-of-two buckets to stderr"
+    )]
+    histogram: bool,
+    #[clap(
+        long,
+        about = "append the summary of the run with a timestamp as a json line to this file, see the trend subcommand"
+    )]
+    append_summary: Option<PathBuf>,
+    #[clap(
+        validator = at_least_one,
+        long,
+        about = "print an aggregated line with the p99 of the last N round trips every N round trips instead of one line per round trip"
+    )]
+    print_every: Option<usize>,
+    #[clap(
+        validator = at_least_one,
+        long,
+        about = "after an idle baseline of --repeat round trips, repeat them with N busy-looping threads and compare"
+    )]
+    stress_cpu: Option<usize>,
+    #[cfg(feature = "tui")]
+    #[clap(
+        long,
+        about = "show a live dashboard instead of per-round-trip lines when stdout is a terminal"
+    )]
+    tui: bool,
+}
+
+impl ClientOpts {
+    /// Total round trips to send: the warmup, the measured repetitions and,
+    /// with `--stress-cpu`, the repetitions under stress.
+    fn round_trips(&self) -> usize {
+        let measured = if self.stress_cpu.is_some() {
+            2 * self.repeat
+        } else {
+            self.repeat
+        };
+        self.warmup + measured
+    }
+
+    fn payloads(&self) -> Payloads {
+        Payloads::new(self.data_size, self.unique_payloads)
+    }
+
+    /// How many samples a run may keep: unbounded for a fixed number of
+    /// round trips, `--max-samples` for runs limited by `--duration`.
+    fn sample_cap(&self) -> Option<usize> {
+        self.duration.map(|_| self.max_samples)
+    }
+
+    /// Index of the first round trip after the baseline of `--stress-cpu` or
+    /// `--stagger-jitter`.
+    fn baseline_end(&self) -> usize {
+        self.warmup + self.repeat
+    }
+
+    fn run_params(&self, target: Option<String>) -> RunParams {
+        RunParams {
+            data_size: self.data_size,
+            repeat: self.duration.is_none().then_some(self.repeat),
+            duration_secs: self.duration,
+            warmup: self.warmup,
+            target,
+        }
+    }
+}
+
+/// Decides when a client loop has sent its last round trip: after
+/// `ClientOpts::round_trips` of them, or with `--duration` once that much
+/// time has passed since the warmup ended.
+struct RunLength {
+    warmup: usize,
+    round_trips: usize,
+    duration: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl RunLength {
+    fn new(opts: &ClientOpts) -> Self {
+        RunLength {
+            warmup: opts.warmup,
+            round_trips: opts.round_trips(),
+            duration: opts.duration.map(Duration::from_secs),
+            deadline: None,
+        }
+    }
+
+    /// Whether round trip `i` should still be sent. A round trip already in
+    /// flight when the deadline passes or Ctrl-C is hit is always allowed to
+    /// finish.
+    fn includes(&mut self, i: usize) -> bool {
+        if stop_requested() {
+            return false;
+        }
+        if i < self.warmup {
+            return true;
+        }
+        if i == self.warmup {
+            self.deadline = self.duration.map(|duration| Instant::now() + duration);
+        }
+        match self.deadline {
+            Some(deadline) => Instant::now() < deadline,
+            None => i < self.round_trips,
+        }
+    }
+
+    /// Adds `extra` round trips to a run of a fixed number of them.
+    fn extend(&mut self, extra: usize) {
+        self.round_trips += extra;
+    }
+
+    /// The number of round trips the run was meant to send, if known upfront.
+    fn total(&self) -> Option<usize> {
+        match self.duration {
+            Some(_) => None,
+            None => Some(self.round_trips),
+        }
+    }
+}
+
+// Validators of the options whose range is narrower than their type. A
+// validator replaces the parse check of the derive, so they report values
+// that do not parse too.
+
+fn at_least_one(s: &str) -> Result<(), String> {
+    match s.parse::<u64>().map_err(|e| e.to_string())? {
+        0 => Err("must be at least 1".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn valid_rate(s: &str) -> Result<(), String> {
+    match s.parse::<u64>().map_err(|e| e.to_string())? {
+        1..=1_000_000_000 => Ok(()),
+        _ => Err("must be within [1, 1000000000]".to_string()),
+    }
+}
+
+fn positive(s: &str) -> Result<(), String> {
+    match s.parse::<f64>().map_err(|e| e.to_string())? {
+        value if value > 0.0 => Ok(()),
+        _ => Err("must be positive".to_string()),
+    }
+}
+
+fn valid_percentage(s: &str) -> Result<(), String> {
+    match s.parse::<f64>().map_err(|e| e.to_string())? {
+        percent if percent > 0.0 && percent <= 100.0 => Ok(()),
+        _ => Err("must be within (0, 100]".to_string()),
+    }
+}
+
+/// Largest `--max-data-size` accepted without `--allow-large`.
+const MAX_DATA_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+/// Initial size of the per-connection receive buffers.
+const INITIAL_RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Exits with a usage error if `max_data_size` is over the limit without
+/// `--allow-large`.
+fn check_max_data_size(max_data_size: usize, allow_large: bool) {
+    if max_data_size > MAX_DATA_SIZE_LIMIT && !allow_large {
+        eprintln!(
+            "error: --max-data-size {} exceeds the {} byte limit, pass --allow-large to override",
+            max_data_size, MAX_DATA_SIZE_LIMIT
+        );
+        std::process::exit(2);
+    }
+}
+
+/// Doubles `buf`, up to `max_size`, if the last read of `size` bytes filled
+/// it completely, so connections only pay for the buffer they actually use.
+fn grow_recv_buffer(buf: &mut Vec<u8>, size: usize, max_size: usize) {
+    if size == buf.len() && buf.len() < max_size {
+        buf.resize((buf.len() * 2).min(max_size), 0);
+    }
+}
+
+/// Converts a `--timeout` in milliseconds into a socket timeout.
+fn timeout_from_millis(timeout: Option<u64>) -> Option<Duration> {
+    timeout.map(Duration::from_millis)
+}
+
+/// Applies `--nodelay` to `stream` and makes its reads and writes fail
+/// instead of blocking past `timeout`.
+fn configure_stream(stream: &TcpStream, nodelay: bool, timeout: Option<Duration>) {
+    stream.set_nodelay(nodelay).unwrap();
+    stream.set_read_timeout(timeout).unwrap();
+    stream.set_write_timeout(timeout).unwrap();
+}
+
+fn start_tcp_forwarder(
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    max_data_size: usize,
+    nodelay: bool,
+    timeout: Option<Duration>,
+) {
     let listener = TcpListener::bind(local_addr).unwrap();
-    let remote_stream = Arc::new(Mutex::new(TcpStream::connect(remote_addr).unwrap()));
+    let remote_stream = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&remote_addr, timeout).unwrap(),
+        None => TcpStream::connect(remote_addr).unwrap(),
+    };
+    // No write timeout on the upstream: every client writes to it, and a
+    // write timing out part way would leave a partial message for the next
+    // client's bytes to be interleaved with. Stalled clients still time out
+    // on their own streams.
+    remote_stream.set_nodelay(nodelay).unwrap();
+    let remote_stream = Arc::new(Mutex::new(remote_stream));
 
     fn handle_client(
         mut from_stream: TcpStream,
-        to_stream: Arc<Mutex<TcpStream>>,
+        to_stream: &Mutex<TcpStream>,
         max_data_size: usize,
     ) {
-        let mut buf = vec![0u8; max_data_size];
+        let mut buf = vec![0u8; max_data_size.min(INITIAL_RECV_BUFFER_SIZE)];
+        // A read timeout ends the loop like an error, closing the idle client.
         while let Ok(size) = from_stream.read(buf.as_mut_slice()) {
+            if size == 0 {
+                break;
+            }
             let mut g = to_stream.lock().unwrap();
-            g.write_all(&buf[..size]).unwrap();
-            g.flush().unwrap();
+            if g.write_all(&buf[..size]).and_then(|_| g.flush()).is_err() {
+                break;
+            }
+            drop(g);
+            grow_recv_buffer(&mut buf, size, max_data_size);
         }
     }
 
+    accept_clients(listener, nodelay, timeout, move |stream| {
+        handle_client(stream, &remote_stream, max_data_size)
+    });
+}
+
+/// Accepts clients on `listener` forever, applying `nodelay` and `timeout`
+/// to each before `handle_client` serves it on a thread of its own.
+fn accept_clients(
+    listener: TcpListener,
+    nodelay: bool,
+    timeout: Option<Duration>,
+    handle_client: impl Fn(TcpStream) + Clone + Send + 'static,
+) {
     for stream in listener.incoming() {
-        let remote = remote_stream.clone();
-        std::thread::spawn(move || handle_client(stream.unwrap(), remote, max_data_size));
+        let stream = stream.unwrap();
+        configure_stream(&stream, nodelay, timeout);
+        let handle_client = handle_client.clone();
+        std::thread::spawn(move || handle_client(stream));
     }
 }
 
 fn start_tcp_tester(
     remote_addr: SocketAddr,
     local_addr: SocketAddr,
-    data_size: usize,
-    repeat: usize,
+    reporter: Reporter,
+    unique_payloads: usize,
+    verbose: bool,
+    nodelay: bool,
 ) {
+    let data_size = reporter.params().data_size;
+    let repeat = reporter.params().repeat.unwrap();
+    let warmup = reporter.params().warmup;
+
     let listener = TcpListener::bind(local_addr).unwrap();
 
     let mut recv_stream = listener.incoming().next().unwrap().unwrap();
@@ -167,45 +757,201 @@ fn start_tcp_tester(
         std::thread::sleep(std::time::Duration::from_secs(1));
         eprintln!("trying to connect {:?}", remote_addr);
     };
+    configure_stream(&recv_stream, nodelay, None);
+    configure_stream(&send_stream, nodelay, None);
 
-    let mut data = vec![0u8; data_size];
+    let payloads = Payloads::new(data_size, unique_payloads);
     let mut buf = vec![0u8; data_size];
+    let mut samples = Vec::with_capacity(repeat);
 
-    for _ in 0..repeat {
-        rand::thread_rng().fill_bytes(data.as_mut_slice());
+    for i in 0..warmup + repeat {
+        if stop_requested() {
+            break;
+        }
+        let data = payloads.next(i);
         let start = Instant::now();
-        send_stream.write_all(data.as_slice()).unwrap();
+        send_stream.write_all(data).unwrap();
         recv_stream.read_exact(buf.as_mut_slice()).unwrap();
         assert_eq!(data, buf);
-        println!("{} us elapsed", start.elapsed().as_micros());
+        if i < warmup {
+            continue;
+        }
+        let elapsed = start.elapsed();
+        let elapsed_us = elapsed.as_secs_f64() * 1e6;
+        if verbose && reporter.is_human() {
+            println!("{} us elapsed", elapsed.as_micros());
+        }
+        reporter.sample(samples.len(), elapsed_us);
+        samples.push(elapsed_us);
     }
+    reporter.finish(&samples, samples.len());
 }
 
-fn start_udp_forwarder(remote_addr: SocketAddr, local_addr: SocketAddr, max_data_size: usize) {
+/// The upstream socket of each client of the udp forwarder, and when that
+/// client last sent a datagram.
+type Upstreams = Arc<Mutex<HashMap<SocketAddr, (UdpSocket, Instant)>>>;
+
+/// Forwards each client's datagrams to `remote_addr` from a dedicated
+/// upstream socket, so the replies arriving on that socket can be relayed
+/// back to the client that sent the request. The upstream of a client idle
+/// for `idle_timeout` is closed, and opened again if the client comes back.
+fn start_udp_forwarder(
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    max_data_size: usize,
+    idle_timeout: Duration,
+) {
     let socket = UdpSocket::bind(local_addr).unwrap();
+    let upstreams: Upstreams = Arc::default();
+
+    fn relay_replies(
+        upstream: UdpSocket,
+        socket: UdpSocket,
+        client_addr: SocketAddr,
+        max_data_size: usize,
+        upstreams: Upstreams,
+        idle_timeout: Duration,
+    ) {
+        upstream.set_read_timeout(Some(idle_timeout)).unwrap();
+        let mut buf = vec![0u8; max_data_size];
+        loop {
+            match upstream.recv(buf.as_mut()) {
+                Ok(size) => {
+                    if socket.send_to(&buf[..size], client_addr).is_err() {
+                        break;
+                    }
+                }
+                // An ICMP port unreachable, e.g. while the remote restarts,
+                // fails the next recv but leaves the socket usable.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    let mut upstreams = upstreams.lock().unwrap();
+                    if upstreams[&client_addr].1.elapsed() >= idle_timeout {
+                        upstreams.remove(&client_addr);
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        // Let the next datagram of the client open a fresh upstream.
+        upstreams.lock().unwrap().remove(&client_addr);
+    }
 
     let mut buf = vec![0u8; max_data_size];
-    while let Ok(size) = socket.recv(buf.as_mut()) {
-        socket.send_to(&buf[..size], remote_addr).unwrap();
+    while let Ok((size, client_addr)) = socket.recv_from(buf.as_mut()) {
+        let mut guard = upstreams.lock().unwrap();
+        let (upstream, last_active) = match guard.entry(client_addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let upstream = match socket::connect_udp(None, remote_addr) {
+                    Ok(upstream) => upstream,
+                    Err(e) => {
+                        eprintln!("failed to open an upstream for {}: {}", client_addr, e);
+                        continue;
+                    }
+                };
+                let (relay, socket) = (upstream.try_clone().unwrap(), socket.try_clone().unwrap());
+                let upstreams = upstreams.clone();
+                std::thread::spawn(move || {
+                    relay_replies(
+                        relay,
+                        socket,
+                        client_addr,
+                        max_data_size,
+                        upstreams,
+                        idle_timeout,
+                    )
+                });
+                entry.insert((upstream, Instant::now()))
+            }
+        };
+        *last_active = Instant::now();
+        // Like a router, drop the datagram if the remote is unreachable.
+        let _ = upstream.send(&buf[..size]);
     }
 }
 
-fn start_tcp_server(addr: SocketAddr, max_data_size: usize) {
-    let listener = TcpListener::bind(addr).unwrap();
+/// A partial-write pattern for the tcp server: each echo is written in
+/// chunks of `bytes` with a `delay` sleep between consecutive chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct Drip {
+    bytes: usize,
+    delay: Duration,
+}
+
+impl FromStr for Drip {
+    type Err = String;
 
-    fn handle_client(mut stream: TcpStream, max_data_size: usize) {
-        let mut buf = vec![0u8; max_data_size];
-        while let Ok(size) = stream.read(buf.as_mut_slice()) {
-            stream.write_all(&buf[..size]).unwrap();
-            stream.flush().unwrap();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bytes, ms) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <bytes>:<ms>, got {:?}", s))?;
+        let bytes: usize = bytes.parse().map_err(|e| format!("invalid bytes: {}", e))?;
+        if bytes == 0 {
+            return Err("chunk size must be at least 1 byte".to_string());
+        }
+        let ms: u64 = ms.parse().map_err(|e| format!("invalid delay: {}", e))?;
+        Ok(Drip {
+            bytes,
+            delay: Duration::from_millis(ms),
+        })
+    }
+}
+
+/// Echoes back whatever the clients accepted on `listener` send.
+fn serve_tcp_echo(
+    listener: TcpListener,
+    max_data_size: usize,
+    drip: Option<Drip>,
+    nodelay: bool,
+    timeout: Option<Duration>,
+) {
+    fn echo(stream: &mut TcpStream, data: &[u8], drip: Option<Drip>) -> io::Result<()> {
+        match drip {
+            Some(drip) => {
+                for (i, chunk) in data.chunks(drip.bytes).enumerate() {
+                    if i > 0 {
+                        std::thread::sleep(drip.delay);
+                    }
+                    stream.write_all(chunk)?;
+                    stream.flush()?;
+                }
+            }
+            None => {
+                stream.write_all(data)?;
+                stream.flush()?;
+            }
         }
+        Ok(())
     }
 
-    for stream in listener.incoming() {
-        std::thread::spawn(move || handle_client(stream.unwrap(), max_data_size));
+    fn handle_client(mut stream: TcpStream, max_data_size: usize, drip: Option<Drip>) {
+        let mut buf = vec![0u8; max_data_size.min(INITIAL_RECV_BUFFER_SIZE)];
+        // A read timeout ends the loop like an error, closing the idle client.
+        while let Ok(size) = stream.read(buf.as_mut_slice()) {
+            if size == 0 {
+                break;
+            }
+            if echo(&mut stream, &buf[..size], drip).is_err() {
+                break;
+            }
+            grow_recv_buffer(&mut buf, size, max_data_size);
+        }
     }
+
+    accept_clients(listener, nodelay, timeout, move |stream| {
+        handle_client(stream, max_data_size, drip)
+    });
 }
 
+/// Echoes every datagram back unchanged, so the sequence number prefixed
+/// by the udp client survives the round trip.
 fn start_udp_server(addr: SocketAddr, max_data_size: usize) {
     let socket = UdpSocket::bind(addr).unwrap();
 
@@ -215,75 +961,1739 @@ fn start_udp_server(addr: SocketAddr, max_data_size: usize) {
     }
 }
 
-fn start_tcp_client(addr: SocketAddr, data_size: usize, repeat: usize) {
-    let mut stream = TcpStream::connect(addr).unwrap();
+/// Like `read_exact`, but records the gap in microseconds between each pair
+/// of consecutive `read` completions into `gaps`.
+fn read_exact_with_gaps(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    gaps: &mut Reservoir<u64>,
+) -> io::Result<()> {
+    let mut filled = 0;
+    let mut last_read: Option<Instant> = None;
+    while filled < buf.len() {
+        let size = stream.read(&mut buf[filled..])?;
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by remote",
+            ));
+        }
+        let now = Instant::now();
+        if let Some(last_read) = last_read {
+            gaps.push(now.duration_since(last_read).as_micros() as u64);
+        }
+        last_read = Some(now);
+        filled += size;
+    }
+    Ok(())
+}
 
-    let mut data: Vec<u8> = vec![0; data_size];
-    let mut recv_data: Vec<u8> = vec![0; data_size];
+fn print_read_gaps(gaps: &Reservoir<u64>, repeat: usize) {
+    println!(
+        "{} reads for {} responses, {} inter-read gaps",
+        gaps.seen() + repeat,
+        repeat,
+        gaps.seen()
+    );
+    if gaps.is_sampled() {
+        println!("counts of a random {} of the gaps:", gaps.items().len());
+    }
+    for (lower, count) in report::histogram_buckets(gaps.items()) {
+        println!("gap >= {:>8} us: {}", lower, count);
+    }
+}
 
-    for _ in 0..repeat {
-        rand::thread_rng().fill_bytes(data.as_mut_slice());
-        let start = Instant::now();
-        stream.write_all(data.as_slice()).unwrap();
-        stream.flush().unwrap();
-        stream.read_exact(recv_data.as_mut_slice()).unwrap();
-        assert_eq!(data, recv_data);
-        println!("{} us elapsed", start.elapsed().as_micros());
+/// Random payloads generated once before the run and cycled round-robin, so
+/// no random data is generated between round trips.
+struct Payloads(Vec<Vec<u8>>);
+
+impl Payloads {
+    fn new(data_size: usize, count: usize) -> Self {
+        Payloads(
+            (0..count)
+                .map(|_| {
+                    let mut data = vec![0u8; data_size];
+                    rand::thread_rng().fill_bytes(data.as_mut_slice());
+                    data
+                })
+                .collect(),
+        )
+    }
+
+    fn next(&self, iteration: usize) -> &[u8] {
+        self.0[iteration % self.0.len()].as_slice()
+    }
+
+    /// Whether the payload of `iteration` has already been sent earlier.
+    fn is_repeated(&self, iteration: usize) -> bool {
+        iteration >= self.0.len()
     }
-    stream.shutdown(Shutdown::Both).unwrap();
 }
 
-fn start_udp_client(local_addr: SocketAddr, data_size: usize, repeat: usize) {
-    let socket = UdpSocket::bind(local_addr).unwrap();
+/// Mean round-trip times of first-seen and repeated payloads.
+#[derive(Default)]
+struct RepeatStats {
+    first_seen: (u128, usize),
+    repeated: (u128, usize),
+}
 
-    let mut data: Vec<u8> = vec![0; data_size];
-    let mut recv_data: Vec<u8> = vec![0; data_size];
+impl RepeatStats {
+    fn record(&mut self, repeated: bool, elapsed: Duration) {
+        let (total, count) = if repeated {
+            &mut self.repeated
+        } else {
+            &mut self.first_seen
+        };
+        *total += elapsed.as_micros();
+        *count += 1;
+    }
 
-    for _ in 0..repeat {
-        rand::thread_rng().fill_bytes(data.as_mut_slice());
-        let start = Instant::now();
-        socket.send(data.as_slice()).unwrap();
-        socket.recv(recv_data.as_mut_slice()).unwrap();
-        assert_eq!(data, recv_data);
-        println!("{} us elapsed", start.elapsed().as_micros());
+    fn print(&self) {
+        for (name, (total, count)) in [("first-seen", self.first_seen), ("repeated", self.repeated)]
+        {
+            if count > 0 {
+                println!(
+                    "{} payloads: {} round trips, {} us mean",
+                    name,
+                    count,
+                    total / count as u128
+                );
+            }
+        }
     }
 }
 
-fn main() {
-    match Opts::parse() {
-        Opts::TcpForwarder {
-            local_socket_addr,
-            remote_socket_addr,
-            max_data_size,
-        } => start_tcp_forwarder(remote_socket_addr, local_socket_addr, max_data_size),
-        Opts::UdpForwarder {
-            local_socket_addr,
-            remote_socket_addr,
-            max_data_size,
-        } => start_udp_forwarder(remote_socket_addr, local_socket_addr, max_data_size),
-        Opts::TcpServer {
-            socket_addr,
-            max_data_size,
-        } => start_tcp_server(socket_addr, max_data_size),
+/// Prints the throughput of `count` round trips completed in `elapsed`. For
+/// a serial request-response loop that is the sum of the round trips, which
+/// makes it the reciprocal of the mean round-trip time.
+fn print_ops_per_sec(count: usize, elapsed: Duration) {
+    if count == 0 {
+        return;
+    }
+    println!("{:.1} ops/sec", count as f64 / elapsed.as_secs_f64());
+}
+
+/// Nearest-rank percentile `p` (in `0.0..=1.0`) of non-empty `samples`.
+fn percentile(samples: &[f64], p: f64) -> f64 {
+    let mut samples = samples.to_vec();
+    let rank = (samples.len() as f64 * p).ceil() as usize;
+    *samples
+        .select_nth_unstable_by(rank.max(1) - 1, |a, b| a.partial_cmp(b).unwrap())
+        .1
+}
+
+/// Busy-looping threads that create cpu contention on the host.
+struct CpuStress {
+    threads: usize,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+    /// The number of samples recorded before the stress started, if it did.
+    baseline: Option<usize>,
+}
+
+impl CpuStress {
+    fn new(threads: usize) -> Self {
+        CpuStress {
+            threads,
+            stop: Arc::new(AtomicBool::new(false)),
+            handles: Vec::new(),
+            baseline: None,
+        }
+    }
+
+    /// Starts the stress threads once `baseline` samples were recorded idle.
+    fn start(&mut self, baseline: usize) {
+        self.baseline = Some(baseline);
+        for _ in 0..self.threads {
+            let stop = self.stop.clone();
+            self.handles.push(std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::hint::spin_loop();
+                }
+            }));
+        }
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for handle in self.handles.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Stops the stress threads and compares the samples taken on an idle
+    /// host against those taken under stress.
+    fn finish(mut self, samples: &[f64]) {
+        self.stop();
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None => return,
+        };
+        let (idle, stressed) = samples.split_at(baseline.min(samples.len()));
+        print_baseline_comparison(
+            "idle baseline",
+            idle,
+            &format!("with {} stress threads", self.threads),
+            stressed,
+        );
+    }
+}
+
+/// Prints the mean and p99 of the `baseline` samples and of the `changed`
+/// ones, with the change in p99, unless either of them is empty.
+fn print_baseline_comparison(
+    baseline_name: &str,
+    baseline: &[f64],
+    changed_name: &str,
+    changed: &[f64],
+) {
+    if baseline.is_empty() || changed.is_empty() {
+        return;
+    }
+    let mean = |samples: &[f64]| samples.iter().sum::<f64>() / samples.len() as f64;
+    println!(
+        "{}: {:.1} us mean, {:.0} us p99",
+        baseline_name,
+        mean(baseline),
+        percentile(baseline, 0.99)
+    );
+    println!(
+        "{}: {:.1} us mean, {:.0} us p99 ({:+.1}% p99)",
+        changed_name,
+        mean(changed),
+        percentile(changed, 0.99),
+        (percentile(changed, 0.99) / percentile(baseline, 0.99) - 1.0) * 100.0
+    );
+}
+
+/// Keeps every item pushed until `cap` of them are kept, then a uniformly
+/// random `cap` of all the items pushed (reservoir sampling), so runs of any
+/// length fit in bounded memory.
+struct Reservoir<T> {
+    items: Vec<T>,
+    cap: Option<usize>,
+    seen: usize,
+}
+
+impl<T> Reservoir<T> {
+    fn new(cap: Option<usize>) -> Self {
+        Reservoir {
+            items: Vec::new(),
+            cap,
+            seen: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        self.seen += 1;
+        match self.cap {
+            Some(cap) if self.items.len() >= cap => {
+                let slot = rand::thread_rng().gen_range(0..self.seen);
+                if slot < cap {
+                    self.items[slot] = item;
+                }
+            }
+            _ => self.items.push(item),
+        }
+    }
+
+    /// Counts `count` more items as pushed without keeping them, for items
+    /// already sampled away by another reservoir.
+    fn skip(&mut self, count: usize) {
+        self.seen += count;
+    }
+
+    fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// The number of items pushed, kept or not.
+    fn seen(&self) -> usize {
+        self.seen
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.seen > self.items.len()
+    }
+}
+
+/// Per-sample bookkeeping shared by the round-trip loops of the clients.
+struct Recorder {
+    verbose: bool,
+    print_every: Option<usize>,
+    /// The samples since the last `--print-every` line.
+    window: Vec<f64>,
+    repeat_stats: Option<RepeatStats>,
+    ops_report: bool,
+    fit: bool,
+    total_elapsed: Duration,
+    /// The wall time of round trips run concurrently, for `--ops-report`.
+    wall_time: Option<Duration>,
+    samples: Reservoir<f64>,
+    statsd: Option<StatsdSink>,
+    #[cfg(feature = "otlp")]
+    otlp: Option<otlp::Exporter>,
+    reporter: Reporter,
+    #[cfg(feature = "tui")]
+    dashboard: Option<tui::Dashboard>,
+}
+
+impl Recorder {
+    /// Creates the recorder of a run against `target`, if it has one.
+    fn new(opts: &ClientOpts, target: Option<String>) -> Self {
+        #[cfg(feature = "otlp")]
+        let otlp = opts
+            .otlp
+            .clone()
+            .map(|endpoint| otlp::Exporter::new(endpoint, target.clone()));
+        Recorder {
+            verbose: opts.verbose,
+            print_every: opts.print_every,
+            window: Vec::with_capacity(opts.print_every.unwrap_or(0)),
+            repeat_stats: opts.payload_repeat.then(RepeatStats::default),
+            ops_report: opts.ops_report,
+            fit: opts.fit,
+            total_elapsed: Duration::ZERO,
+            wall_time: None,
+            samples: Reservoir::new(opts.sample_cap()),
+            statsd: opts
+                .statsd
+                .map(|addr| StatsdSink::new(addr, opts.statsd_metric.clone())),
+            #[cfg(feature = "otlp")]
+            otlp,
+            reporter: Reporter::new(
+                opts.output_format,
+                opts.run_params(target),
+                opts.histogram,
+                opts.append_summary.clone(),
+            ),
+            #[cfg(feature = "tui")]
+            dashboard: if opts.tui && opts.output_format == OutputFormat::Human {
+                tui::Dashboard::new()
+            } else {
+                None
+            },
+        }
+    }
+
+    fn record(&mut self, repeated: bool, elapsed: Duration) {
+        self.add(repeated, elapsed);
+        if self.reporter.is_human() {
+            self.print_progress(elapsed);
+        }
+    }
+
+    /// Like `record`, but without human progress output, for samples merged
+    /// from other threads after they finished.
+    fn add(&mut self, repeated: bool, elapsed: Duration) {
+        let elapsed_us = elapsed.as_secs_f64() * 1e6;
+        self.total_elapsed += elapsed;
+        self.samples.push(elapsed_us);
+        self.reporter.sample(self.samples.seen() - 1, elapsed_us);
+        if let Some(repeat_stats) = &mut self.repeat_stats {
+            repeat_stats.record(repeated, elapsed);
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.emit(elapsed);
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = &mut self.otlp {
+            otlp.round_trip(self.samples.seen() - 1, elapsed);
+        }
+    }
+
+    fn print_progress(&mut self, elapsed: Duration) {
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.update(
+                self.samples.items(),
+                self.samples.seen(),
+                elapsed.as_secs_f64() * 1e6,
+            );
+            return;
+        }
+        match self.print_every {
+            Some(print_every) => {
+                self.window.push(elapsed.as_secs_f64() * 1e6);
+                if self.window.len() == print_every {
+                    println!(
+                        "{} round trips, last {} us, p99 {:.0} us over the last {}",
+                        self.samples.seen(),
+                        elapsed.as_micros(),
+                        percentile(&self.window, 0.99),
+                        print_every
+                    );
+                    self.window.clear();
+                }
+            }
+            None if self.verbose => println!("{} us elapsed", elapsed.as_micros()),
+            None => {}
+        }
+    }
+
+    /// Shows the udp `packets` counters on the dashboard, which redraws on
+    /// loss too rather than only when an echo is recorded.
+    #[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+    fn update_packets(&mut self, packets: PacketStats) {
+        #[cfg(feature = "tui")]
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.update_packets(packets, self.samples.items(), self.samples.seen());
+        }
+    }
+
+    /// The number of round trips recorded, including any whose samples
+    /// were not kept.
+    fn count(&self) -> usize {
+        self.samples.seen()
+    }
+
+    /// Reports `--ops-report` over the `wall_time` of round trips run
+    /// concurrently rather than over the sum of their times.
+    fn set_wall_time(&mut self, wall_time: Duration) {
+        self.wall_time = Some(wall_time);
+    }
+
+    /// Counts `count` round trips measured elsewhere whose samples were not
+    /// kept.
+    fn skip(&mut self, count: usize) {
+        self.samples.skip(count);
+    }
+
+    fn is_human(&self) -> bool {
+        self.reporter.is_human()
+    }
+
+    /// Stops `stress`, comparing the idle and stressed samples in human
+    /// output.
+    fn finish_stress(&self, stress: Option<CpuStress>) {
+        if let Some(mut stress) = stress {
+            if self.is_human() {
+                stress.finish(self.samples.items());
+            } else {
+                stress.stop();
+            }
+        }
+    }
+
+    fn finish(self) {
+        // Leave the dashboard screen before printing the final report.
+        #[cfg(feature = "tui")]
+        drop(self.dashboard);
+        let samples = self.samples.items();
+        self.reporter.finish(samples, self.samples.seen());
+        if self.samples.is_sampled() {
+            eprintln!(
+                "the statistics are of a random {} of the {} round trips, see --max-samples",
+                samples.len(),
+                self.samples.seen()
+            );
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = self.otlp {
+            otlp.finish();
+        }
+        if !self.reporter.is_human() {
+            return;
+        }
+        if let Some(repeat_stats) = &self.repeat_stats {
+            repeat_stats.print();
+        }
+        if self.ops_report {
+            print_ops_per_sec(
+                self.samples.seen(),
+                self.wall_time.unwrap_or(self.total_elapsed),
+            );
+        }
+        if self.fit {
+            fit::print_fits(samples);
+        }
+    }
+}
+
+/// Set by the first Ctrl-C to end the round-trip loops early.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+fn stop_requested() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+extern "C" fn handle_sigint(_: libc::c_int) {
+    // A second Ctrl-C aborts a loop stuck waiting for a reply.
+    if STOP.swap(true, Ordering::Relaxed) {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Makes Ctrl-C stop the round-trip loops after the current round trip, so
+/// the samples gathered so far are still reported.
+#[cfg(unix)]
+fn install_stop_handler() {
+    // SAFETY: the handler only touches an atomic and calls `_exit`.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle_sigint as *const () as libc::sighandler_t,
+        )
+    };
+}
+
+#[cfg(not(unix))]
+fn install_stop_handler() {}
+
+#[cfg(unix)]
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, writable timespec.
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    assert_eq!(ret, 0, "clock_gettime failed");
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Duration {
+    panic!("--cpu-quota is only supported on unix platforms");
+}
+
+/// Keeps the cpu usage of the measuring thread under a quota by sleeping
+/// between round trips, trading timing precision for less interference
+/// with whatever else shares the host.
+struct CpuQuota {
+    quota: f64,
+    start: Instant,
+    start_cpu: Duration,
+}
+
+impl CpuQuota {
+    fn new(percent: f64) -> Self {
+        CpuQuota {
+            quota: percent / 100.0,
+            start: Instant::now(),
+            start_cpu: thread_cpu_time(),
+        }
+    }
+
+    fn throttle(&self) {
+        let cpu = (thread_cpu_time() - self.start_cpu).as_secs_f64();
+        let wall = self.start.elapsed().as_secs_f64();
+        let target_wall = cpu / self.quota;
+        if target_wall > wall {
+            std::thread::sleep(Duration::from_secs_f64(target_wall - wall));
+        }
+    }
+
+    fn report(&self) {
+        let cpu = (thread_cpu_time() - self.start_cpu).as_secs_f64();
+        println!(
+            "measuring thread cpu usage: {:.1}% achieved, {:.1}% quota",
+            cpu / self.start.elapsed().as_secs_f64() * 100.0,
+            self.quota * 100.0
+        );
+    }
+}
+
+/// Paces round trips to a fixed rate. Send times are scheduled from the start
+/// of the run rather than from the previous send, so jitter doesn't make the
+/// schedule drift; a round trip that overruns its slot is followed by the
+/// next one immediately and counted as behind schedule.
+struct Pacer {
+    rate: u64,
+    interval_ns: u64,
+    start: Instant,
+    sends: usize,
+    behind: usize,
+}
+
+impl Pacer {
+    fn new(rate: u64) -> Self {
+        Pacer {
+            rate,
+            interval_ns: 1_000_000_000 / rate,
+            start: Instant::now(),
+            sends: 0,
+            behind: 0,
+        }
+    }
+
+    /// Sleeps until the scheduled time of round trip `i`.
+    fn wait(&mut self, i: usize) {
+        let scheduled = self.start + Duration::from_nanos(self.interval_ns * i as u64);
+        let now = Instant::now();
+        if scheduled > now {
+            std::thread::sleep(scheduled - now);
+        } else if i > 0 {
+            self.behind += 1;
+        }
+        self.sends = i + 1;
+    }
+
+    fn report(&self) {
+        println!(
+            "send rate: {:.1}/s achieved, {}/s target, {} of {} sends behind schedule",
+            self.sends as f64 / self.start.elapsed().as_secs_f64(),
+            self.rate,
+            self.behind,
+            self.sends
+        );
+    }
+}
+
+/// Emits round-trip times as statsd timing metrics over UDP.
+///
+/// The socket is non-blocking and send errors are ignored, so a slow or
+/// missing collector never stalls the measurement loop.
+struct StatsdSink {
+    socket: UdpSocket,
+    metric: String,
+}
+
+impl StatsdSink {
+    fn new(addr: SocketAddr, metric: String) -> Self {
+        let local_addr: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local_addr).unwrap();
+        socket.connect(addr).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        StatsdSink { socket, metric }
+    }
+
+    fn emit(&self, elapsed: Duration) {
+        let line = format!("{}:{}|ms", self.metric, elapsed.as_secs_f64() * 1000.0);
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+/// A byte-level token bucket that paces writes to a target bandwidth.
+struct Shaper {
+    bytes_per_sec: f64,
+    chunk_size: usize,
+    tokens: f64,
+    last_refill: Instant,
+    bytes_sent: u64,
+    time_spent: Duration,
+}
+
+impl Shaper {
+    fn new(mbps: f64) -> Self {
+        let bytes_per_sec = mbps * 1e6 / 8.0;
+        // Release roughly a millisecond's worth of bytes at a time.
+        let chunk_size = ((bytes_per_sec / 1000.0) as usize).clamp(1, 65536);
+        Shaper {
+            bytes_per_sec,
+            chunk_size,
+            tokens: chunk_size as f64,
+            last_refill: Instant::now(),
+            bytes_sent: 0,
+            time_spent: Duration::ZERO,
+        }
+    }
+
+    fn write_all(&mut self, stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+        let start = Instant::now();
+        for chunk in data.chunks(self.chunk_size) {
+            let now = Instant::now();
+            self.tokens = (self.tokens
+                + now.duration_since(self.last_refill).as_secs_f64() * self.bytes_per_sec)
+                .min(self.chunk_size as f64);
+            self.last_refill = now;
+            let needed = chunk.len() as f64 - self.tokens;
+            if needed > 0.0 {
+                std::thread::sleep(Duration::from_secs_f64(needed / self.bytes_per_sec));
+                self.tokens += needed;
+                self.last_refill = Instant::now();
+            }
+            self.tokens -= chunk.len() as f64;
+            stream.write_all(chunk)?;
+        }
+        self.bytes_sent += data.len() as u64;
+        self.time_spent += start.elapsed();
+        Ok(())
+    }
+
+    fn achieved_mbps(&self) -> f64 {
+        self.bytes_sent as f64 * 8.0 / 1e6 / self.time_spent.as_secs_f64()
+    }
+}
+
+/// Prints the distributions of the time spent in `write_all`/`flush` and in
+/// `read_exact`. Long writes point at a full send buffer (backpressure),
+/// while the read time is the wait for the response.
+fn print_split_timing(write_times: &[f64], read_times: &[f64]) {
+    if write_times.is_empty() {
+        return;
+    }
+    for (name, times) in [("write", write_times), ("read", read_times)] {
+        println!(
+            "{}: {:.1} us mean, {:.0} us p50, {:.0} us p99, {:.0} us max",
+            name,
+            times.iter().sum::<f64>() / times.len() as f64,
+            percentile(times, 0.5),
+            percentile(times, 0.99),
+            percentile(times, 1.0)
+        );
+    }
+}
+
+/// Why a client run did not complete cleanly.
+#[derive(Debug)]
+enum ClientError {
+    /// An I/O error ended the run after `completed` of `total` round trips,
+    /// where `total` is unknown for runs limited by `--duration`.
+    Aborted {
+        completed: usize,
+        total: Option<usize>,
+        source: io::Error,
+    },
+    /// `corrupted` of `total` echoes did not match the payload that was sent.
+    Corrupted { corrupted: usize, total: usize },
+    /// `oversized` of `total` udp echoes were longer than the datagram sent.
+    Oversized { oversized: usize, total: usize },
+    /// `failed` of `total` `--connections` did not complete cleanly.
+    Connections { failed: usize, total: usize },
+    /// The client could not connect to `target`.
+    Connect { target: String, source: io::Error },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Aborted {
+                completed,
+                total: Some(total),
+                source,
+            } => write!(
+                f,
+                "aborting after {}/{} iterations: {}",
+                completed, total, source
+            ),
+            ClientError::Aborted {
+                completed,
+                total: None,
+                source,
+            } => write!(f, "aborting after {} iterations: {}", completed, source),
+            ClientError::Corrupted { corrupted, total } => write!(
+                f,
+                "{}/{} echoes did not match the sent payload",
+                corrupted, total
+            ),
+            ClientError::Oversized { oversized, total } => write!(
+                f,
+                "{}/{} echoes were oversized datagrams, longer than the payload sent",
+                oversized, total
+            ),
+            ClientError::Connections { failed, total } => {
+                write!(f, "{}/{} connections failed", failed, total)
+            }
+            ClientError::Connect { target, source } => {
+                write!(f, "failed to connect to {}: {}", target, source)?;
+                // Connection errors often wrap the os error that explains them.
+                let mut cause = std::error::Error::source(source);
+                while let Some(inner) = cause.and_then(|e| e.source()) {
+                    cause = Some(inner);
+                }
+                match cause {
+                    Some(cause) => write!(f, ": {}", cause),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Turns the outcome of a client loop that completed `sent` round trips into
+/// its result, preferring the I/O error that ended the run over any
+/// oversized or corrupted echoes seen before it.
+fn client_result(
+    error: Option<io::Error>,
+    sent: usize,
+    run: &RunLength,
+    oversized: usize,
+    corrupted: usize,
+) -> Result<(), ClientError> {
+    match error {
+        Some(source) => Err(ClientError::Aborted {
+            completed: sent,
+            total: run.total(),
+            source,
+        }),
+        None if oversized > 0 => Err(ClientError::Oversized {
+            oversized,
+            total: sent,
+        }),
+        None if corrupted > 0 => Err(ClientError::Corrupted {
+            corrupted,
+            total: sent,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// What a client loop reports back to the client that ran it.
+struct ClientRun {
+    /// Whether the output format leaves room for client-specific reports.
+    human: bool,
+    /// The number of round trips measured after the warmup.
+    measured: usize,
+    result: Result<(), ClientError>,
+}
+
+/// Runs the round-trip loop shared by the clients against `target`, if the
+/// run has one. `round_trip(i, payload)` sends round trip `i` and returns
+/// its time and echo, or `None` if the echo was lost. Warmup, run length,
+/// `--stress-cpu`, `--cpu-quota` and `rate` pacing are handled here, and the
+/// common reports are printed before returning. The `packets` counted by a
+/// datagram client are passed on to the dashboard after each round trip.
+fn run_client_loop(
+    client_opts: &ClientOpts,
+    target: Option<String>,
+    rate: Option<u64>,
+    packets: Option<&Cell<PacketStats>>,
+    mut round_trip: impl FnMut(usize, &[u8]) -> io::Result<Option<(Duration, Echo)>>,
+) -> ClientRun {
+    let mut recorder = Recorder::new(client_opts, target);
+    let cpu_quota = client_opts.cpu_quota.map(CpuQuota::new);
+    let payloads = client_opts.payloads();
+
+    let mut stress = client_opts.stress_cpu.map(CpuStress::new);
+    let mut run = RunLength::new(client_opts);
+    let mut pacer = rate.map(Pacer::new);
+
+    let mut sent = 0;
+    let mut oversized = 0;
+    let mut corrupted = 0;
+    let mut error = None;
+    for i in 0.. {
+        if !run.includes(i) {
+            break;
+        }
+        // Lost round trips keep their slot in the schedule too.
+        if let Some(pacer) = &mut pacer {
+            pacer.wait(i);
+        }
+        if i == client_opts.baseline_end() {
+            if let Some(stress) = &mut stress {
+                // Lost round trips leave no sample, so split the samples at
+                // their count rather than at the round trip index.
+                stress.start(recorder.count());
+            }
+        }
+        let repeated = payloads.is_repeated(i);
+        let result = round_trip(i, payloads.next(i));
+        if let Some(packets) = packets {
+            recorder.update_packets(packets.get());
+        }
+        let (elapsed, echo) = match result {
+            Ok(Some(echo)) => echo,
+            // Lost, move on to the next round trip.
+            Ok(None) => {
+                sent += 1;
+                continue;
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        sent += 1;
+        match echo {
+            Echo::Matched => {}
+            Echo::Corrupted => corrupted += 1,
+            Echo::Oversized => oversized += 1,
+        }
+        // Warmup round trips are verified but never timed.
+        if i < client_opts.warmup {
+            continue;
+        }
+        recorder.record(repeated, elapsed);
+        if let Some(cpu_quota) = &cpu_quota {
+            cpu_quota.throttle();
+        }
+    }
+
+    recorder.finish_stress(stress);
+    let measured = recorder.count();
+    let human = recorder.is_human();
+    recorder.finish();
+    if human {
+        if let Some(cpu_quota) = &cpu_quota {
+            cpu_quota.report();
+        }
+        if let Some(pacer) = &pacer {
+            pacer.report();
+        }
+    }
+    ClientRun {
+        human,
+        measured,
+        result: client_result(error, sent, &run, oversized, corrupted),
+    }
+}
+
+/// Writes `data` and reads its echo into `recv_data`, returning when the
+/// write and the read completed.
+fn tcp_round_trip(
+    stream: &mut TcpStream,
+    shaper: Option<&mut Shaper>,
+    data: &[u8],
+    recv_data: &mut [u8],
+    gaps: Option<&mut Reservoir<u64>>,
+) -> io::Result<(Instant, Instant)> {
+    match shaper {
+        Some(shaper) => shaper.write_all(stream, data)?,
+        None => stream.write_all(data)?,
+    }
+    stream.flush()?;
+    let written = Instant::now();
+    match gaps {
+        Some(gaps) => read_exact_with_gaps(stream, recv_data, gaps)?,
+        None => stream.read_exact(recv_data)?,
+    }
+    Ok((written, Instant::now()))
+}
+
+fn start_tcp_client(
+    mut stream: TcpStream,
+    client_opts: ClientOpts,
+    read_gaps: bool,
+    split_timing: bool,
+    shape_mbps: Option<f64>,
+    rate: Option<u64>,
+) -> Result<(), ClientError> {
+    let mut shaper = shape_mbps.map(Shaper::new);
+    let target = stream.peer_addr().ok().map(|addr| addr.to_string());
+    let warmup = client_opts.warmup;
+
+    let mut recv_data: Vec<u8> = vec![0; client_opts.data_size];
+    let mut gaps = Reservoir::new(client_opts.sample_cap());
+    let mut write_times = Reservoir::new(client_opts.sample_cap());
+    let mut read_times = Reservoir::new(client_opts.sample_cap());
+
+    let client_run = run_client_loop(&client_opts, target, rate, None, |i, data| {
+        let start = Instant::now();
+        let (written, read) = tcp_round_trip(
+            &mut stream,
+            shaper.as_mut(),
+            data,
+            recv_data.as_mut_slice(),
+            (read_gaps && i >= warmup).then_some(&mut gaps),
+        )?;
+        if split_timing && i >= warmup {
+            write_times.push((written - start).as_secs_f64() * 1e6);
+            read_times.push((read - written).as_secs_f64() * 1e6);
+        }
+        Ok(Some((read - start, Echo::compare(data, &recv_data))))
+    });
+    // The remote may already be gone if the run was aborted.
+    let _ = stream.shutdown(Shutdown::Both);
+
+    if !client_run.human {
+        return client_run.result;
+    }
+    if read_gaps {
+        print_read_gaps(&gaps, client_run.measured);
+    }
+    if split_timing {
+        print_split_timing(write_times.items(), read_times.items());
+    }
+    if let (Some(shaper), Some(target)) = (shaper, shape_mbps) {
+        println!(
+            "shaped send bandwidth: {:.3} Mbit/s achieved, {:.3} Mbit/s target",
+            shaper.achieved_mbps(),
+            target
+        );
+    }
+    client_run.result
+}
+
+/// Length of the little-endian sequence number that prefixes udp probes.
+const SEQUENCE_LEN: usize = 8;
+
+/// Loss and reordering of the datagrams sent by the udp client.
+#[derive(Clone, Copy, Default)]
+struct PacketStats {
+    sent: usize,
+    /// Echoes that arrived before their datagram timed out.
+    received: usize,
+    /// Datagrams whose echo did not arrive within `--recv-timeout`.
+    lost: usize,
+    /// Echoes of lost datagrams that arrived after all.
+    late: usize,
+    out_of_order: usize,
+    highest_seen: Option<u64>,
+}
+
+impl PacketStats {
+    fn record_sequence(&mut self, sequence: u64) {
+        if self.highest_seen.is_some_and(|highest| sequence < highest) {
+            self.out_of_order += 1;
+        } else {
+            self.highest_seen = Some(sequence);
+        }
+    }
+
+    fn print(&self) {
+        println!(
+            "packets: {} sent, {} received, {} lost, {} late echoes, {} out of order",
+            self.sent, self.received, self.lost, self.late, self.out_of_order
+        );
+    }
+}
+
+/// Waits for the echo of the datagram numbered `sequence` and returns its
+/// length, counting and skipping late echoes of earlier datagrams. Returns
+/// `None` and counts the datagram as lost if the socket's read timeout
+/// passes first. Without a sequence number any datagram is taken for the
+/// echo.
+fn recv_echo(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    sequence: Option<u64>,
+    packets: &mut PacketStats,
+) -> io::Result<Option<usize>> {
+    loop {
+        let size = match socket::recv_datagram(socket, buf) {
+            Ok(size) => size,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                packets.lost += 1;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let sequence = match sequence {
+            Some(sequence) if size >= SEQUENCE_LEN => sequence,
+            _ => {
+                packets.received += 1;
+                return Ok(Some(size));
+            }
+        };
+        let received = u64::from_le_bytes(buf[..SEQUENCE_LEN].try_into().unwrap());
+        packets.record_sequence(received);
+        if received >= sequence {
+            packets.received += 1;
+            return Ok(Some(size));
+        }
+        packets.late += 1;
+    }
+}
+
+/// How an echo compared to the payload that was sent.
+#[derive(Debug, PartialEq)]
+enum Echo {
+    Matched,
+    Corrupted,
+    /// A udp echo longer than the datagram sent.
+    Oversized,
+}
+
+impl Echo {
+    /// Compares an echo received in full against the payload sent.
+    fn compare(sent: &[u8], received: &[u8]) -> Self {
+        if sent == received {
+            Echo::Matched
+        } else {
+            Echo::Corrupted
+        }
+    }
+}
+
+/// Sends `send_data` and waits for its echo into `recv_data`, which must be
+/// as long as `send_data`. Returns the round-trip time and how the echo
+/// compared, or `None` if it was lost.
+fn udp_round_trip(
+    socket: &UdpSocket,
+    send_data: &[u8],
+    recv_data: &mut [u8],
+    sequence: Option<u64>,
+    packets: &mut PacketStats,
+) -> io::Result<Option<(Duration, Echo)>> {
+    let start = Instant::now();
+    socket.send(send_data)?;
+    packets.sent += 1;
+    let size = match recv_echo(socket, recv_data, sequence, packets)? {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    let elapsed = start.elapsed();
+    // Unlike a zero-byte read on a stream, a zero-byte recv is not an end of
+    // stream but an empty datagram: the expected echo when probing with
+    // `--data-size 0`, and a short reply otherwise, which counts as
+    // corrupted.
+    let echo = if size > send_data.len() {
+        Echo::Oversized
+    } else if size < send_data.len() || send_data != recv_data {
+        Echo::Corrupted
+    } else {
+        Echo::Matched
+    };
+    Ok(Some((elapsed, echo)))
+}
+
+/// The samples and outcome of one of the `--connections` round-trip loops.
+struct ConnectionRun {
+    samples: Reservoir<(bool, Duration)>,
+    /// With `--stagger-jitter`, the number of samples of the lock-step
+    /// baseline, if the run got past it.
+    baseline: Option<usize>,
+    result: Result<(), ClientError>,
+}
+
+fn run_tcp_connection(
+    mut stream: TcpStream,
+    client_opts: &ClientOpts,
+    sample_cap: Option<usize>,
+    stagger_jitter: Option<u64>,
+) -> ConnectionRun {
+    // Generated on this thread, so connections don't share an rng.
+    let payloads = client_opts.payloads();
+    let mut recv_data: Vec<u8> = vec![0; client_opts.data_size];
+    let mut run = RunLength::new(client_opts);
+    if stagger_jitter.is_some() {
+        run.extend(client_opts.repeat);
+    }
+
+    let mut samples = Reservoir::new(sample_cap);
+    let mut baseline = None;
+    let mut sent = 0;
+    let mut corrupted = 0;
+    let mut error = None;
+    for i in 0.. {
+        if !run.includes(i) {
+            break;
+        }
+        if let Some(max_us) = stagger_jitter {
+            if i == client_opts.baseline_end() {
+                baseline = Some(samples.seen());
+            }
+            if baseline.is_some() {
+                let pause = rand::thread_rng().gen_range(0..=max_us);
+                std::thread::sleep(Duration::from_micros(pause));
+            }
+        }
+        let repeated = payloads.is_repeated(i);
+        let data = payloads.next(i);
+        let start = Instant::now();
+        let read = match tcp_round_trip(&mut stream, None, data, recv_data.as_mut_slice(), None) {
+            Ok((_, read)) => read,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        sent += 1;
+        if data != recv_data.as_slice() {
+            corrupted += 1;
+        }
+        if i >= client_opts.warmup {
+            samples.push((repeated, read - start));
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    ConnectionRun {
+        samples,
+        baseline,
+        result: client_result(error, sent, &run, 0, corrupted),
+    }
+}
+
+/// Runs the tcp client loop over `connections` streams opened by `connect`,
+/// each on its own thread, and reports their merged samples.
+fn start_tcp_connections(
+    connect: &(dyn Fn() -> io::Result<TcpStream> + Sync),
+    target: SocketAddr,
+    client_opts: ClientOpts,
+    connections: usize,
+    stagger_jitter: Option<u64>,
+) -> Result<(), ClientError> {
+    // Split the cap so the merged samples stay within it.
+    let sample_cap = client_opts
+        .sample_cap()
+        .map(|cap| (cap / connections).max(1));
+    let start = Instant::now();
+    let runs: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..connections)
+            .map(|_| {
+                scope.spawn(|| match connect() {
+                    Ok(stream) => {
+                        run_tcp_connection(stream, &client_opts, sample_cap, stagger_jitter)
+                    }
+                    // A connection that failed to connect only loses its own
+                    // samples.
+                    Err(source) => ConnectionRun {
+                        samples: Reservoir::new(None),
+                        baseline: None,
+                        result: Err(ClientError::Connect {
+                            target: target.to_string(),
+                            source,
+                        }),
+                    },
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let wall_time = start.elapsed();
+
+    let mut recorder = Recorder::new(&client_opts, Some(target.to_string()));
+    recorder.set_wall_time(wall_time);
+    let mut failed = 0;
+    let (mut lock_step, mut staggered) = (Vec::new(), Vec::new());
+    for (i, run) in runs.into_iter().enumerate() {
+        if let Err(e) = &run.result {
+            eprintln!("connection {}: {}", i, e);
+            failed += 1;
+        }
+        if client_opts.verbose && recorder.is_human() && !run.samples.items().is_empty() {
+            let samples: Vec<f64> = run
+                .samples
+                .items()
+                .iter()
+                .map(|(_, elapsed)| elapsed.as_secs_f64() * 1e6)
+                .collect();
+            println!(
+                "connection {}: {} round trips, {:.1} us mean, {:.0} us p50, {:.0} us p99",
+                i,
+                run.samples.seen(),
+                samples.iter().sum::<f64>() / samples.len() as f64,
+                percentile(&samples, 0.5),
+                percentile(&samples, 0.99)
+            );
+        }
+        for &(repeated, elapsed) in run.samples.items() {
+            recorder.add(repeated, elapsed);
+        }
+        recorder.skip(run.samples.seen() - run.samples.items().len());
+        if let Some(baseline) = run.baseline {
+            let (before, after) = run.samples.items().split_at(baseline);
+            let us = |&(_, elapsed): &(bool, Duration)| elapsed.as_secs_f64() * 1e6;
+            lock_step.extend(before.iter().map(us));
+            staggered.extend(after.iter().map(us));
+        }
+    }
+    if let (Some(max_us), true) = (stagger_jitter, recorder.is_human()) {
+        print_baseline_comparison(
+            "lock-step baseline",
+            &lock_step,
+            &format!("staggered by up to {} us", max_us),
+            &staggered,
+        );
+    }
+    recorder.finish();
+    if failed > 0 {
+        Err(ClientError::Connections {
+            failed,
+            total: connections,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn start_udp_client(
+    socket: UdpSocket,
+    client_opts: ClientOpts,
+    recv_timeout: Duration,
+    rate: Option<u64>,
+) -> Result<(), ClientError> {
+    let data_size = client_opts.data_size;
+    let target = socket.peer_addr().ok().map(|addr| addr.to_string());
+
+    socket.set_read_timeout(Some(recv_timeout)).unwrap();
+    // Probes without a sequence number can still detect loss, but not tell a
+    // late echo from the current one. --payload-repeat needs the repeated
+    // payloads to be identical, so it goes without them too.
+    let sequenced = data_size >= SEQUENCE_LEN && !client_opts.payload_repeat;
+    if client_opts.payload_repeat {
+        eprintln!("--payload-repeat sends the payloads without sequence numbers, reordering is not detected");
+    } else if !sequenced {
+        eprintln!(
+            "--data-size below {} bytes leaves no room for sequence numbers, reordering is not detected",
+            SEQUENCE_LEN
+        );
+    }
+    let packets = Cell::new(PacketStats::default());
+
+    let mut send_data: Vec<u8> = vec![0; data_size];
+    let mut recv_data: Vec<u8> = vec![0; data_size];
+
+    let client_run = run_client_loop(&client_opts, target, rate, Some(&packets), |i, data| {
+        send_data.copy_from_slice(data);
+        let sequence = sequenced.then_some(i as u64);
+        if let Some(sequence) = sequence {
+            send_data[..SEQUENCE_LEN].copy_from_slice(&sequence.to_le_bytes());
+        }
+        let mut counted = packets.get();
+        let echo = udp_round_trip(
+            &socket,
+            &send_data,
+            recv_data.as_mut_slice(),
+            sequence,
+            &mut counted,
+        );
+        packets.set(counted);
+        echo
+    });
+
+    if client_run.human {
+        packets.get().print();
+    }
+    client_run.result
+}
+
+/// Runs the client loop against an echo thread connected by in-memory
+/// channels, so the reported round trips are the latency floor of the
+/// measurement pipeline itself, without any OS networking in between.
+fn start_inproc(client_opts: ClientOpts) -> Result<(), ClientError> {
+    let (request_tx, request_rx) = mpsc::channel::<Vec<u8>>();
+    let (reply_tx, reply_rx) = mpsc::channel::<Vec<u8>>();
+    let echo = std::thread::spawn(move || {
+        for data in request_rx {
+            if reply_tx.send(data).is_err() {
+                break;
+            }
+        }
+    });
+
+    let client_run = run_client_loop(&client_opts, None, None, None, |_, data| {
+        let start = Instant::now();
+        // Copy the payload as a socket write would, so that cost is kept in.
+        request_tx.send(data.to_vec()).unwrap();
+        let recv_data = reply_rx.recv().unwrap();
+        Ok(Some((start.elapsed(), Echo::compare(data, &recv_data))))
+    });
+    drop(request_tx);
+    echo.join().unwrap();
+    client_run.result
+}
+
+fn start_conn_stress(addr: SocketAddr, duration: Duration, concurrency: usize) {
+    let start = Instant::now();
+    let deadline = start + duration;
+
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let mut handshakes = Vec::new();
+                let mut failures: HashMap<String, usize> = HashMap::new();
+                // Bounding each connect by the time left keeps a server that
+                // drops SYNs from holding a worker past the deadline.
+                while let Some(left) = deadline
+                    .checked_duration_since(Instant::now())
+                    .filter(|left| !left.is_zero())
+                {
+                    let start = Instant::now();
+                    match TcpStream::connect_timeout(&addr, left) {
+                        Ok(_) => handshakes.push(start.elapsed().as_secs_f64() * 1e6),
+                        // The connect was cut short by the end of the test,
+                        // not failed by the server.
+                        Err(_) if Instant::now() >= deadline => break,
+                        Err(e) => *failures.entry(format!("{:?}", e.kind())).or_default() += 1,
+                    }
+                }
+                (handshakes, failures)
+            })
+        })
+        .collect();
+
+    let mut handshakes = Vec::new();
+    let mut failures: HashMap<String, usize> = HashMap::new();
+    for worker in workers {
+        let (worker_handshakes, worker_failures) = worker.join().unwrap();
+        handshakes.extend(worker_handshakes);
+        for (kind, count) in worker_failures {
+            *failures.entry(kind).or_default() += count;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "{} connections in {:.1} s: {:.1} connections/sec",
+        handshakes.len(),
+        elapsed,
+        handshakes.len() as f64 / elapsed
+    );
+    if !handshakes.is_empty() {
+        println!(
+            "handshake: {:.0} us p50, {:.0} us p90, {:.0} us p99, {:.0} us max",
+            percentile(&handshakes, 0.5),
+            percentile(&handshakes, 0.9),
+            percentile(&handshakes, 0.99),
+            percentile(&handshakes, 1.0)
+        );
+    }
+    let mut failures: Vec<_> = failures.into_iter().collect();
+    failures.sort();
+    for (kind, count) in failures {
+        println!("failed with {}: {}", kind, count);
+    }
+}
+
+/// Reports a failed client run on stderr and exits with a nonzero status.
+fn exit_on_client_error(result: Result<(), ClientError>) {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let opts = Opts::parse();
+    if matches!(
+        opts,
+        Opts::TcpTester { .. }
+            | Opts::TcpClient { .. }
+            | Opts::UdpClient { .. }
+            | Opts::GrpcClient { .. }
+            | Opts::WebSocketClient { .. }
+            | Opts::Inproc { .. }
+    ) {
+        install_stop_handler();
+    }
+    match opts {
+        Opts::TcpForwarder {
+            local_socket_addr,
+            remote_socket_addr,
+            max_data_size,
+            allow_large,
+            nodelay,
+            timeout,
+        } => {
+            check_max_data_size(max_data_size, allow_large);
+            start_tcp_forwarder(
+                remote_socket_addr,
+                local_socket_addr,
+                max_data_size,
+                nodelay,
+                timeout_from_millis(timeout),
+            )
+        }
+        Opts::UdpForwarder {
+            local_socket_addr,
+            remote_socket_addr,
+            max_data_size,
+            idle_timeout,
+        } => start_udp_forwarder(
+            remote_socket_addr,
+            local_socket_addr,
+            max_data_size,
+            Duration::from_secs(idle_timeout),
+        ),
+        Opts::TcpServer {
+            socket_addr,
+            max_data_size,
+            allow_large,
+            drip,
+            nodelay,
+            timeout,
+        } => {
+            check_max_data_size(max_data_size, allow_large);
+            serve_tcp_echo(
+                TcpListener::bind(socket_addr).unwrap(),
+                max_data_size,
+                drip,
+                nodelay,
+                timeout_from_millis(timeout),
+            )
+        }
         Opts::UdpServer {
             socket_addr,
             max_data_size,
         } => start_udp_server(socket_addr, max_data_size),
         Opts::TcpClient {
             socket_addr,
-            data_size,
-            repeat,
-        } => start_tcp_client(socket_addr, data_size, repeat),
+            fd,
+            bind,
+            client_opts,
+            read_gaps,
+            split_timing,
+            mss,
+            vrf,
+            priority,
+            source_port_range,
+            shape_mbps,
+            rate,
+            nodelay,
+            timeout,
+            connections,
+            stagger_jitter,
+        } => {
+            let timeout = timeout_from_millis(timeout);
+            if connections != 1 || stagger_jitter.is_some() {
+                let addr = socket_addr.unwrap();
+                let connect_options = ConnectOptions {
+                    mss,
+                    vrf,
+                    priority,
+                    bind,
+                    source_ports: source_port_range,
+                    connect_timeout: timeout,
+                };
+                let connect = || {
+                    let stream = connect_options.connect_tcp(addr)?;
+                    configure_stream(&stream, nodelay, timeout);
+                    Ok(stream)
+                };
+                exit_on_client_error(start_tcp_connections(
+                    &connect,
+                    addr,
+                    client_opts,
+                    connections,
+                    stagger_jitter,
+                ));
+            } else {
+                let stream = match fd {
+                    Some(fd) => socket::tcp_stream_from_fd(fd),
+                    None => {
+                        let addr = socket_addr.unwrap();
+                        let connected = ConnectOptions {
+                            mss,
+                            vrf,
+                            priority,
+                            bind,
+                            source_ports: source_port_range,
+                            connect_timeout: timeout,
+                        }
+                        .connect_tcp(addr);
+                        match connected {
+                            Ok(stream) => stream,
+                            Err(source) => {
+                                return exit_on_client_error(Err(ClientError::Connect {
+                                    target: addr.to_string(),
+                                    source,
+                                }))
+                            }
+                        }
+                    }
+                };
+                configure_stream(&stream, nodelay, timeout);
+                exit_on_client_error(start_tcp_client(
+                    stream,
+                    client_opts,
+                    read_gaps,
+                    split_timing,
+                    shape_mbps,
+                    rate,
+                ));
+            }
+        }
         Opts::UdpClient {
-            local_addr,
-            data_size,
-            repeat,
-        } => start_udp_client(local_addr, data_size, repeat),
+            socket_addr,
+            bind,
+            fd,
+            recv_timeout,
+            no_gso,
+            no_gro,
+            rate,
+            client_opts,
+        } => {
+            let socket = match fd {
+                Some(fd) => socket::udp_socket_from_fd(fd),
+                None => {
+                    let addr = socket_addr.unwrap();
+                    match socket::connect_udp(bind, addr) {
+                        Ok(socket) => socket,
+                        Err(source) => {
+                            return exit_on_client_error(Err(ClientError::Connect {
+                                target: addr.to_string(),
+                                source,
+                            }))
+                        }
+                    }
+                }
+            };
+            socket::disable_udp_offloads(&socket, no_gso, no_gro);
+            exit_on_client_error(start_udp_client(
+                socket,
+                client_opts,
+                Duration::from_millis(recv_timeout),
+                rate,
+            ))
+        }
         Opts::TcpTester {
             local_socket_addr,
             remote_socket_addr,
             data_size,
             repeat,
-        } => start_tcp_tester(remote_socket_addr, local_socket_addr, data_size, repeat),
+            warmup,
+            unique_payloads,
+            verbose,
+            output_format,
+            histogram,
+            nodelay,
+        } => start_tcp_tester(
+            remote_socket_addr,
+            local_socket_addr,
+            Reporter::new(
+                output_format,
+                RunParams {
+                    data_size,
+                    repeat: Some(repeat),
+                    duration_secs: None,
+                    warmup,
+                    target: Some(remote_socket_addr.to_string()),
+                },
+                histogram,
+                None,
+            ),
+            unique_payloads,
+            verbose,
+            nodelay,
+        ),
+        Opts::GrpcServer { socket_addr } => grpc::start_grpc_server(socket_addr),
+        Opts::GrpcClient {
+            socket_addr,
+            client_opts,
+        } => exit_on_client_error(grpc::start_grpc_client(socket_addr, client_opts)),
+        Opts::WebSocketClient { url, client_opts } => {
+            exit_on_client_error(websocket::start_websocket_client(url, client_opts))
+        }
+        Opts::Inproc { client_opts } => exit_on_client_error(start_inproc(client_opts)),
+        Opts::ConnStress {
+            socket_addr,
+            duration,
+            concurrency,
+        } => start_conn_stress(socket_addr, Duration::from_secs(duration), concurrency),
+        Opts::Trend {
+            file,
+            statistic,
+            target,
+        } => {
+            if let Err(e) = trend::print_trend(&file, statistic, target.as_deref()) {
+                eprintln!("failed to read {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes a single datagram back to its sender and returns its length.
+    fn echo_once(server: UdpSocket) -> JoinHandle<usize> {
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (size, client_addr) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&buf[..size], client_addr).unwrap();
+            size
+        })
+    }
+
+    #[test]
+    fn nodelay_is_applied_to_both_ends() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        for nodelay in [true, false] {
+            let client = ConnectOptions::default().connect_tcp(addr).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            configure_stream(&client, nodelay, None);
+            configure_stream(&server, nodelay, None);
+            assert_eq!(client.nodelay().unwrap(), nodelay);
+            assert_eq!(server.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    #[test]
+    fn zero_length_udp_probe_round_trips() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = socket::connect_udp(None, server.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let echo = echo_once(server);
+
+        let mut packets = PacketStats::default();
+        let echoed = udp_round_trip(&client, &[], &mut [], None, &mut packets).unwrap();
+        assert_eq!(echo.join().unwrap(), 0);
+        assert!(matches!(echoed, Some((_, Echo::Matched))), "{:?}", echoed);
+        assert_eq!((packets.sent, packets.received, packets.lost), (1, 1, 0));
+    }
+
+    #[test]
+    fn drip_parses_bytes_and_delay() {
+        let drip: Drip = "16:5".parse().unwrap();
+        assert_eq!(drip.bytes, 16);
+        assert_eq!(drip.delay, Duration::from_millis(5));
+
+        let drip: Drip = "1:0".parse().unwrap();
+        assert_eq!((drip.bytes, drip.delay), (1, Duration::ZERO));
+    }
+
+    #[test]
+    fn drip_rejects_malformed_specs() {
+        for spec in [
+            "", "16", "16:", ":5", "0:5", "-1:5", "16:-5", "16:5ms", "a:b",
+        ] {
+            assert!(spec.parse::<Drip>().is_err(), "{:?} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn validators_reject_values_out_of_range_or_unparsable() {
+        assert!(at_least_one("1").is_ok());
+        assert!(at_least_one("0").is_err());
+        assert!(at_least_one("-1").is_err());
+        assert!(valid_rate("1000000000").is_ok());
+        assert!(valid_rate("1000000001").is_err());
+        assert!(valid_rate("0").is_err());
+        assert!(positive("0.5").is_ok());
+        assert!(positive("0").is_err());
+        assert!(positive("NaN").is_err());
+        assert!(valid_percentage("100").is_ok());
+        assert!(valid_percentage("100.5").is_err());
+        assert!(valid_percentage("0").is_err());
+        assert!(valid_percentage("half").is_err());
+    }
+
+    #[test]
+    fn accepted_streams_get_nodelay() {
+        for nodelay in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (accepted, nodelays) = mpsc::channel();
+            std::thread::spawn(move || {
+                accept_clients(listener, nodelay, None, move |stream| {
+                    accepted.send(stream.nodelay().unwrap()).unwrap()
+                })
+            });
+            let _client = TcpStream::connect(addr).unwrap();
+            let accepted = nodelays.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(accepted, nodelay);
+        }
+    }
+
+    #[test]
+    fn tcp_server_echoes_dripped_writes_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let drip = Drip {
+            bytes: 3,
+            delay: Duration::from_millis(1),
+        };
+        std::thread::spawn(move || serve_tcp_echo(listener, 1024, Some(drip), true, None));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        configure_stream(&client, true, Some(Duration::from_secs(5)));
+        let data: Vec<u8> = (0..100).collect();
+        let mut echoed = vec![0; data.len()];
+        client.write_all(&data).unwrap();
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, data);
     }
 }
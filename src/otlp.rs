@@ -0,0 +1,407 @@
+//! Export of the round trips as OpenTelemetry spans over OTLP/HTTP.
+//!
+//! The spans are sent in the json encoding of OTLP, which collectors accept
+//! on the same `/v1/traces` path as protobuf, so the export needs neither the
+//! OpenTelemetry SDK nor a second protobuf stack next to the gRPC one.
+
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+
+use crate::report::json_string;
+
+/// Spans queued beyond this are dropped rather than stalling the loop.
+const QUEUE_LEN: usize = 65536;
+/// A batch is exported once it holds this many spans, or after
+/// `BATCH_INTERVAL` if it is smaller.
+const BATCH_LEN: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An `http://host[:port][/path]` OTLP/HTTP traces endpoint.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.starts_with("https://") {
+            return Err("https:// is not supported: this build has no TLS support".to_string());
+        } else {
+            return Err(format!(
+                "unsupported otlp endpoint {:?}, expected http://host[:port][/path]",
+                url
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) if pos + 1 < rest.len() => (&rest[..pos], &rest[pos..]),
+            Some(pos) => (&rest[..pos], "/v1/traces"),
+            None => (rest, "/v1/traces"),
+        };
+        // The colons of a bracketed ipv6 address do not start a port.
+        let port = authority
+            .rsplit_once(':')
+            .filter(|(_, port)| !port.ends_with(']'));
+        let (host, port) = match port {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|e| format!("invalid port in otlp endpoint: {}", e))?,
+            ),
+            None => (authority, 4318),
+        };
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// A span of a round trip, or of the whole run if it has no `iteration`.
+struct Span {
+    span_id: [u8; 8],
+    iteration: Option<usize>,
+    start_ns: u64,
+    end_ns: u64,
+}
+
+/// What the spans of a run share.
+struct Trace {
+    trace_id: [u8; 16],
+    run_id: [u8; 8],
+    target: Option<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// The json `ExportTraceServiceRequest` of `spans`.
+fn export_request(trace: &Trace, spans: &[Span]) -> String {
+    let mut json = String::from(
+        "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\",\"value\":{\"stringValue\":\"network-latency\"}}]},\"scopeSpans\":[{\"scope\":{\"name\":\"network-latency\"},\"spans\":[",
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write!(
+            json,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{}\"",
+            hex(&trace.trace_id),
+            hex(&span.span_id)
+        )
+        .unwrap();
+        let mut attributes = Vec::new();
+        if let Some(target) = &trace.target {
+            attributes.push(format!(
+                "{{\"key\":\"network_latency.target\",\"value\":{{\"stringValue\":{}}}}}",
+                json_string(target)
+            ));
+        }
+        match span.iteration {
+            Some(iteration) => {
+                // Round trips are client spans, children of the run.
+                write!(
+                    json,
+                    ",\"parentSpanId\":\"{}\",\"name\":\"round trip\",\"kind\":3",
+                    hex(&trace.run_id)
+                )
+                .unwrap();
+                attributes.push(format!(
+                    "{{\"key\":\"network_latency.iteration\",\"value\":{{\"intValue\":\"{}\"}}}}",
+                    iteration
+                ));
+            }
+            None => json.push_str(",\"name\":\"run\",\"kind\":1"),
+        }
+        write!(
+            json,
+            ",\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}]}}",
+            span.start_ns,
+            span.end_ns,
+            attributes.join(",")
+        )
+        .unwrap();
+    }
+    json.push_str("]}]}]}");
+    json
+}
+
+/// Posts the json `body` to `endpoint`, failing unless the collector
+/// accepts it with a 2xx status.
+fn post(endpoint: &Endpoint, body: &str) -> io::Result<()> {
+    let host = endpoint.host.trim_start_matches('[').trim_end_matches(']');
+    let addr = (host, endpoint.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("otlp host did not resolve to any address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}:{}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.host,
+        endpoint.port,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector replied {:?}",
+            status.trim_end()
+        ))),
+    }
+}
+
+/// Spans that could not be exported and the first error that lost them.
+#[derive(Default)]
+struct Failures {
+    spans: usize,
+    error: Option<io::Error>,
+}
+
+/// Exports the `spans` received until the exporter hangs up, in batches.
+fn export_batches(endpoint: Endpoint, trace: Trace, spans: Receiver<Span>) -> Failures {
+    let mut failures = Failures::default();
+    let mut batch = Vec::with_capacity(BATCH_LEN);
+    let mut deadline = Instant::now() + BATCH_INTERVAL;
+    loop {
+        let closed = match spans.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if batch.len() < BATCH_LEN && Instant::now() < deadline && !closed {
+            continue;
+        }
+        if !batch.is_empty() {
+            if let Err(e) = post(&endpoint, &export_request(&trace, &batch)) {
+                failures.spans += batch.len();
+                failures.error.get_or_insert(e);
+            }
+            batch.clear();
+        }
+        if closed {
+            return failures;
+        }
+        deadline = Instant::now() + BATCH_INTERVAL;
+    }
+}
+
+/// Queues a span per round trip, under one span for the whole run, for a
+/// background thread that exports them in batches.
+pub struct Exporter {
+    endpoint: Endpoint,
+    spans: SyncSender<Span>,
+    thread: JoinHandle<Failures>,
+    run_id: [u8; 8],
+    start_ns: u64,
+    dropped: usize,
+}
+
+impl Exporter {
+    /// Starts exporting to `endpoint` the spans of a run against `target`, if
+    /// it has one.
+    pub fn new(endpoint: Endpoint, target: Option<String>) -> Self {
+        let mut trace = Trace {
+            trace_id: [0; 16],
+            run_id: [0; 8],
+            target,
+        };
+        rand::thread_rng().fill_bytes(&mut trace.trace_id);
+        rand::thread_rng().fill_bytes(&mut trace.run_id);
+        let run_id = trace.run_id;
+        let (spans, received) = mpsc::sync_channel(QUEUE_LEN);
+        let thread_endpoint = endpoint.clone();
+        let thread = thread::spawn(move || export_batches(thread_endpoint, trace, received));
+        Exporter {
+            endpoint,
+            spans,
+            thread,
+            run_id,
+            start_ns: unix_nanos(SystemTime::now()),
+            dropped: 0,
+        }
+    }
+
+    /// Queues the span of the measured round trip `iteration`, which ended
+    /// just now after `elapsed`.
+    pub fn round_trip(&mut self, iteration: usize, elapsed: Duration) {
+        let end_ns = unix_nanos(SystemTime::now());
+        let mut span = Span {
+            span_id: [0; 8],
+            iteration: Some(iteration),
+            start_ns: end_ns.saturating_sub(elapsed.as_nanos() as u64),
+            end_ns,
+        };
+        rand::thread_rng().fill_bytes(&mut span.span_id);
+        if let Err(TrySendError::Full(_)) = self.spans.try_send(span) {
+            self.dropped += 1;
+        }
+    }
+
+    /// Ends the span of the run and waits for the queued spans to be
+    /// exported, reporting those that were lost to stderr.
+    pub fn finish(self) {
+        let _ = self.spans.send(Span {
+            span_id: self.run_id,
+            iteration: None,
+            start_ns: self.start_ns,
+            end_ns: unix_nanos(SystemTime::now()),
+        });
+        drop(self.spans);
+        let failures = self.thread.join().unwrap();
+        if self.dropped > 0 {
+            eprintln!(
+                "dropped {} otlp spans the export could not keep up with",
+                self.dropped
+            );
+        }
+        if let Some(e) = failures.error {
+            eprintln!(
+                "failed to export {} otlp spans to {}: {}",
+                failures.spans, self.endpoint, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn endpoints_default_to_the_traces_path_and_port() {
+        let endpoint: Endpoint = "http://collector".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "http://collector:4318/v1/traces");
+        let endpoint: Endpoint = "http://127.0.0.1:4000/".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "http://127.0.0.1:4000/v1/traces");
+        let endpoint: Endpoint = "http://[::1]:4000/otlp/v1/traces".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "http://[::1]:4000/otlp/v1/traces");
+        let endpoint: Endpoint = "http://[::1]".parse().unwrap();
+        assert_eq!(endpoint.to_string(), "http://[::1]:4318/v1/traces");
+        assert!("https://collector".parse::<Endpoint>().is_err());
+        assert!("collector:4318".parse::<Endpoint>().is_err());
+        assert!("http://collector:port".parse::<Endpoint>().is_err());
+    }
+
+    /// Answers one request on `listener` with `status` and returns its body.
+    fn collect_once(listener: TcpListener, status: &'static str) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(reader.get_mut(), "HTTP/1.1 {}\r\n\r\n", status).unwrap();
+            String::from_utf8(body).unwrap()
+        })
+    }
+
+    #[test]
+    fn round_trips_are_exported_under_the_run_span() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = collect_once(listener, "200 OK");
+
+        let mut exporter = Exporter::new(endpoint.parse().unwrap(), Some("a\"b".to_string()));
+        let run_id = hex(&exporter.run_id);
+        exporter.round_trip(0, Duration::from_micros(250));
+        exporter.round_trip(1, Duration::from_micros(300));
+        exporter.finish();
+
+        let body = collector.join().unwrap();
+        assert_eq!(body.matches("\"name\":\"round trip\"").count(), 2);
+        assert_eq!(body.matches("\"name\":\"run\"").count(), 1);
+        assert_eq!(
+            body.matches(&format!("\"parentSpanId\":\"{}\"", run_id))
+                .count(),
+            2
+        );
+        assert!(body.contains(&format!("\"spanId\":\"{}\"", run_id)));
+        assert!(body.contains("{\"intValue\":\"1\"}"));
+        assert!(body.contains("{\"stringValue\":\"a\\\"b\"}"));
+    }
+
+    #[test]
+    fn rejected_exports_are_counted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint: Endpoint = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let collector = collect_once(listener, "400 Bad Request");
+        let trace = Trace {
+            trace_id: [1; 16],
+            run_id: [2; 8],
+            target: None,
+        };
+        let (spans, received) = mpsc::sync_channel(4);
+        for iteration in 0..3 {
+            spans
+                .send(Span {
+                    span_id: [3; 8],
+                    iteration: Some(iteration),
+                    start_ns: 0,
+                    end_ns: 1,
+                })
+                .unwrap();
+        }
+        drop(spans);
+        let failures = export_batches(endpoint, trace, received);
+        collector.join().unwrap();
+        assert_eq!(failures.spans, 3);
+        assert!(failures.error.unwrap().to_string().contains("400"));
+    }
+}
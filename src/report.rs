@@ -0,0 +1,357 @@
+//! Output of the client and tester results in the `--output-format` chosen.
+
+use std::fmt::Write;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Human,
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format {:?}, expected human, csv or json",
+                s
+            )),
+        }
+    }
+}
+
+/// The parameters of a run, echoed back in the json output.
+#[derive(Debug)]
+pub struct RunParams {
+    pub data_size: usize,
+    /// `None` for runs limited by `--duration`.
+    pub repeat: Option<usize>,
+    pub duration_secs: Option<u64>,
+    pub warmup: usize,
+    /// `None` if the run has no network peer.
+    pub target: Option<String>,
+}
+
+/// Count, min, max, mean, stddev and nearest-rank percentiles of round-trip
+/// samples in microseconds.
+struct Summary {
+    /// The round trips measured, including those not kept as samples.
+    count: usize,
+    /// The samples the statistics are computed from.
+    kept: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+    stddev: f64,
+    percentiles: [(&'static str, f64); 4],
+}
+
+impl Summary {
+    fn new(samples: &[f64], count: usize) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = sorted.len() as f64;
+        let mean = sorted.iter().sum::<f64>() / n;
+        let stddev = (sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let nearest_rank = |p: f64| sorted[((n * p).ceil() as usize).max(1) - 1];
+        Some(Summary {
+            count,
+            kept: sorted.len(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean,
+            stddev,
+            percentiles: [
+                ("p50", nearest_rank(0.5)),
+                ("p90", nearest_rank(0.9)),
+                ("p99", nearest_rank(0.99)),
+                ("p99.9", nearest_rank(0.999)),
+            ],
+        })
+    }
+
+    fn print(&self) {
+        println!("count:  {}", self.count);
+        println!("min:    {:.0} us", self.min);
+        println!("max:    {:.0} us", self.max);
+        println!("mean:   {:.1} us", self.mean);
+        println!("stddev: {:.1} us", self.stddev);
+        for (name, value) in self.percentiles {
+            println!("{:<8}{:.0} us", format!("{}:", name), value);
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"count\":{},\"kept\":{},\"min_us\":{:.3},\"max_us\":{:.3},\"mean_us\":{:.3},\"stddev_us\":{:.3}",
+            self.count, self.kept, self.min, self.max, self.mean, self.stddev
+        );
+        for (name, value) in self.percentiles {
+            write!(json, ",\"{}_us\":{:.3}", name.replace('.', "_"), value).unwrap();
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Escapes `s` as a json string literal.
+pub fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+/// Formats `unix_time` in seconds as an RFC 3339 UTC timestamp.
+fn utc_timestamp(unix_time: u64) -> String {
+    let (days, secs) = (unix_time / 86400, unix_time % 86400);
+    // Howard Hinnant's civil_from_days, on eras of 400 years from 0000-03-01.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Counts `samples` into power-of-two buckets and returns the lower bound and
+/// count of each bucket from the lowest to the highest non-empty one. Zero
+/// gets a bucket of its own.
+pub fn histogram_buckets(samples: &[u64]) -> Vec<(u64, usize)> {
+    let bucket = |sample: u64| (64 - sample.leading_zeros()) as usize;
+    let (first, last) = match (samples.iter().min(), samples.iter().max()) {
+        (Some(&min), Some(&max)) => (bucket(min), bucket(max)),
+        _ => return Vec::new(),
+    };
+    let mut counts = vec![0; last - first + 1];
+    for &sample in samples {
+        counts[bucket(sample) - first] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| match first + i {
+            0 => (0, count),
+            i => (1 << (i - 1), count),
+        })
+        .collect()
+}
+
+/// Prints an ascii bar chart of the power-of-two buckets of `samples` (in
+/// microseconds) to stderr.
+fn print_histogram(samples: &[f64]) {
+    const WIDTH: usize = 50;
+    let samples: Vec<u64> = samples.iter().map(|&sample| sample as u64).collect();
+    let buckets = histogram_buckets(&samples);
+    let max = buckets.iter().map(|&(_, count)| count).max().unwrap_or(0);
+    for (lower, count) in buckets {
+        eprintln!(
+            ">= {:>8} us | {:<width$} {}",
+            lower,
+            "#".repeat((count * WIDTH).div_ceil(max)),
+            count,
+            width = WIDTH
+        );
+    }
+}
+
+/// Writes the measured samples and their summary to stdout in the chosen
+/// format, and optionally a histogram to stderr.
+pub struct Reporter {
+    format: OutputFormat,
+    params: RunParams,
+    histogram: bool,
+    append_summary: Option<PathBuf>,
+}
+
+impl Reporter {
+    pub fn new(
+        format: OutputFormat,
+        params: RunParams,
+        histogram: bool,
+        append_summary: Option<PathBuf>,
+    ) -> Self {
+        if format == OutputFormat::Csv {
+            println!("iteration,elapsed_us");
+        }
+        Reporter {
+            format,
+            params,
+            histogram,
+            append_summary,
+        }
+    }
+
+    pub fn params(&self) -> &RunParams {
+        &self.params
+    }
+
+    /// Whether free-form progress lines and extra reports may go to stdout.
+    pub fn is_human(&self) -> bool {
+        self.format == OutputFormat::Human
+    }
+
+    /// Reports the measured sample number `iteration`, in microseconds.
+    pub fn sample(&self, iteration: usize, elapsed_us: f64) {
+        if self.format == OutputFormat::Csv {
+            println!("{},{:.3}", iteration, elapsed_us);
+        }
+    }
+
+    /// Reports the run once all `count` round trips are measured, of which
+    /// `samples` (in microseconds) were kept.
+    pub fn finish(&self, samples: &[f64], count: usize) {
+        if self.histogram {
+            print_histogram(samples);
+        }
+        let summary = Summary::new(samples, count);
+        match self.format {
+            OutputFormat::Human => {
+                if let Some(summary) = &summary {
+                    summary.print();
+                }
+            }
+            OutputFormat::Csv => {}
+            OutputFormat::Json => {
+                let samples: Vec<String> = samples.iter().map(|x| format!("{:.3}", x)).collect();
+                println!(
+                    "{{{},\"samples_us\":[{}],\"summary\":{}}}",
+                    self.params_json(),
+                    samples.join(","),
+                    json_option(summary.as_ref().map(Summary::to_json))
+                );
+            }
+        }
+        if let (Some(path), Some(summary)) = (&self.append_summary, &summary) {
+            if let Err(e) = self.append_summary(path, summary) {
+                eprintln!("failed to append the summary to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The run parameters as the fields of a json object.
+    fn params_json(&self) -> String {
+        let params = &self.params;
+        format!(
+            "\"data_size\":{},\"repeat\":{},\"duration_secs\":{},\"warmup\":{},\"target\":{}",
+            params.data_size,
+            json_option(params.repeat),
+            json_option(params.duration_secs),
+            params.warmup,
+            json_option(params.target.as_deref().map(json_string))
+        )
+    }
+
+    /// Appends the run parameters and `summary`, stamped with the current
+    /// time, as one json line to `path` for the `trend` subcommand.
+    fn append_summary(&self, path: &Path, summary: &Summary) -> io::Result<()> {
+        use std::io::Write;
+
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let line = format!(
+            "{{\"timestamp\":{},\"unix_time\":{},{},\"summary\":{}}}\n",
+            json_string(&utc_timestamp(unix_time)),
+            unix_time,
+            self.params_json(),
+            summary.to_json()
+        );
+        // One write per record keeps concurrent runs from interleaving lines.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_timestamps_follow_the_calendar() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(1792049230), "2026-10-15T07:27:10Z");
+        assert_eq!(utc_timestamp(4107542399), "2100-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn summary_counts_round_trips_beyond_the_kept_samples() {
+        let summary = Summary::new(&[1.0, 2.0, 3.0], 10).unwrap();
+        assert_eq!((summary.count, summary.kept), (10, 3));
+        assert!(summary
+            .to_json()
+            .starts_with("{\"count\":10,\"kept\":3,\"min_us\":1.000,"));
+        assert!(Summary::new(&[], 10).is_none());
+    }
+
+    #[test]
+    fn histogram_of_no_samples_is_empty() {
+        assert_eq!(histogram_buckets(&[]), vec![]);
+    }
+
+    #[test]
+    fn zero_one_and_two_get_buckets_of_their_own() {
+        assert_eq!(
+            histogram_buckets(&[0, 1, 2, 2]),
+            vec![(0, 1), (1, 1), (2, 2)]
+        );
+    }
+
+    #[test]
+    fn powers_of_two_open_their_bucket() {
+        assert_eq!(
+            histogram_buckets(&[4, 7, 8, 15, 16]),
+            vec![(4, 2), (8, 2), (16, 1)]
+        );
+        assert_eq!(histogram_buckets(&[1 << 63]), vec![(1 << 63, 1)]);
+    }
+
+    #[test]
+    fn empty_buckets_between_samples_are_kept() {
+        assert_eq!(
+            histogram_buckets(&[3, 40]),
+            vec![(2, 1), (4, 0), (8, 0), (16, 0), (32, 1)]
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// An inclusive range of local ports, parsed from `<start>:<end>`.
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <start>:<end>, got {:?}", s))?;
+        let start: u16 = start
+            .parse()
+            .map_err(|e| format!("invalid start port: {}", e))?;
+        let end: u16 = end
+            .parse()
+            .map_err(|e| format!("invalid end port: {}", e))?;
+        if start == 0 || start > end {
+            return Err(format!("invalid port range {}:{}", start, end));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+/// Socket options applied to a client socket before it connects.
+#[derive(Debug, Default)]
+pub struct ConnectOptions {
+    /// Maximum segment size to request via `TCP_MAXSEG`.
+    pub mss: Option<u32>,
+    /// VRF device to bind to via `SO_BINDTODEVICE`.
+    pub vrf: Option<String>,
+    /// Queueing priority to set via `SO_PRIORITY`.
+    pub priority: Option<u32>,
+    /// Local address to bind before connecting.
+    pub bind: Option<SocketAddr>,
+    /// Local ports to try binding, in order, before connecting.
+    pub source_ports: Option<PortRange>,
+    /// How long to wait for the connection to be established.
+    pub connect_timeout: Option<Duration>,
+}
+
+impl ConnectOptions {
+    pub fn connect_tcp(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(mss) = self.mss {
+            set_mss(&socket, mss)?;
+        }
+        if let Some(vrf) = &self.vrf {
+            bind_vrf(&socket, vrf)?;
+        }
+        if let Some(priority) = self.priority {
+            set_priority(&socket, priority)?;
+        }
+        if let Some(bind) = self.bind {
+            socket
+                .bind(&bind.into())
+                .map_err(|e| with_context(e, format_args!("failed to bind {}", bind)))?;
+        }
+        if let Some(ports) = self.source_ports {
+            bind_source_port(&socket, addr, ports)?;
+        }
+        match self.connect_timeout {
+            Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+            None => socket.connect(&addr.into())?,
+        }
+        if self.mss.is_some() {
+            report_mss(&socket);
+        }
+        Ok(socket.into())
+    }
+}
+
+/// Prefixes the message of `e` with what failed, keeping its kind.
+fn with_context(e: io::Error, context: impl fmt::Display) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", context, e))
+}
+
+/// The unspecified address of the same family as `remote`.
+pub fn unspecified_ip(remote: SocketAddr) -> IpAddr {
+    match remote {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Binds a udp socket to `bind`, or by default to an ephemeral port on the
+/// unspecified address, and connects it to `remote`.
+pub fn connect_udp(bind: Option<SocketAddr>, remote: SocketAddr) -> io::Result<UdpSocket> {
+    let bind = bind.unwrap_or_else(|| SocketAddr::new(unspecified_ip(remote), 0));
+    let socket = UdpSocket::bind(bind)
+        .map_err(|e| with_context(e, format_args!("failed to bind {}", bind)))?;
+    socket.connect(remote)?;
+    Ok(socket)
+}
+
+/// Binds `socket` to the first free port of `ports` on the unspecified
+/// address of the same family as `remote`.
+fn bind_source_port(socket: &Socket, remote: SocketAddr, ports: PortRange) -> io::Result<()> {
+    let ip = unspecified_ip(remote);
+    for port in ports.start..=ports.end {
+        match socket.bind(&SocketAddr::new(ip, port).into()) {
+            Ok(()) => {
+                eprintln!("bound to source port {}", port);
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => {
+                return Err(with_context(
+                    e,
+                    format_args!("failed to bind source port {}", port),
+                ))
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free source port in range {}:{}", ports.start, ports.end),
+    ))
+}
+
+/// Receives one datagram into `buf` and returns its full length, which is
+/// larger than `buf.len()` if the datagram had to be truncated.
+#[cfg(target_os = "linux")]
+pub fn recv_datagram(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: `MaybeUninit<u8>` has the same layout as `u8`, and the buffer
+    // is already initialized.
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    // With MSG_TRUNC Linux returns the real datagram length even if it did
+    // not fit into the buffer.
+    SockRef::from(socket).recv_with_flags(buf, libc::MSG_TRUNC)
+}
+
+/// Receives one datagram into `buf`. Truncation cannot be detected on this
+/// platform, so the returned length never exceeds `buf.len()`.
+#[cfg(not(target_os = "linux"))]
+pub fn recv_datagram(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<usize> {
+    socket.recv(buf)
+}
+
+/// Takes ownership of a connected socket of type `ty` handed over by another
+/// process as a raw file descriptor.
+#[cfg(unix)]
+fn socket_from_fd(fd: RawFd, ty: Type) -> Socket {
+    // SAFETY: the caller hands the descriptor over to us; it is validated as
+    // a socket below and closed again if unusable.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    match socket.r#type() {
+        Ok(actual) if actual == ty => {}
+        Ok(actual) => panic!("fd {} is a {:?} socket, expected {:?}", fd, actual, ty),
+        Err(e) => panic!("fd {} is not a usable socket: {}", fd, e),
+    }
+    if let Err(e) = socket.peer_addr() {
+        panic!("fd {} is not a connected socket: {}", fd, e);
+    }
+    socket
+}
+
+#[cfg(unix)]
+pub fn tcp_stream_from_fd(fd: RawFd) -> TcpStream {
+    socket_from_fd(fd, Type::STREAM).into()
+}
+
+#[cfg(unix)]
+pub fn udp_socket_from_fd(fd: RawFd) -> UdpSocket {
+    socket_from_fd(fd, Type::DGRAM).into()
+}
+
+#[cfg(not(unix))]
+pub fn tcp_stream_from_fd(_fd: i32) -> TcpStream {
+    panic!("--fd is only supported on unix platforms");
+}
+
+#[cfg(not(unix))]
+pub fn udp_socket_from_fd(_fd: i32) -> UdpSocket {
+    panic!("--fd is only supported on unix platforms");
+}
+
+#[cfg(target_os = "linux")]
+fn set_mss(socket: &Socket, mss: u32) -> io::Result<()> {
+    socket
+        .set_mss(mss)
+        .map_err(|e| with_context(e, format_args!("failed to set mss {}", mss)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mss(_socket: &Socket, _mss: u32) -> io::Result<()> {
+    eprintln!("--mss is only supported on Linux, using the default segment size");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn report_mss(socket: &Socket) {
+    match socket.mss() {
+        Ok(mss) => eprintln!("effective mss: {} bytes", mss),
+        Err(e) => eprintln!("failed to read the effective mss: {}", e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn report_mss(_socket: &Socket) {}
+
+#[cfg(target_os = "linux")]
+fn bind_vrf(socket: &Socket, vrf: &str) -> io::Result<()> {
+    if let Err(e) = socket.bind_device(Some(vrf.as_bytes())) {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            return Err(with_context(
+                e,
+                format_args!("binding to vrf {:?} requires CAP_NET_RAW", vrf),
+            ));
+        }
+        return Err(with_context(
+            e,
+            format_args!("failed to bind to vrf {:?}", vrf),
+        ));
+    }
+    eprintln!("bound to vrf {}", vrf);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_vrf(_socket: &Socket, _vrf: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--vrf is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and its size is passed along.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt_int(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` outlive the call and `len` holds its size.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_priority(socket: &Socket, priority: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    setsockopt_int(
+        socket.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_PRIORITY,
+        priority as libc::c_int,
+    )
+    .map_err(|e| {
+        with_context(
+            e,
+            format_args!("failed to set socket priority {}", priority),
+        )
+    })?;
+    eprintln!("socket priority: {}", priority);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_priority(_socket: &Socket, _priority: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--priority is only supported on Linux",
+    ))
+}
+
+/// Disables UDP generic segmentation offload (`UDP_SEGMENT`) and/or generic
+/// receive offload (`UDP_GRO`) on `socket`, reporting for each offload
+/// whether it was turned off, already off or could not be changed. Both
+/// start out off on a new socket, so this only matters for sockets handed
+/// over with `--fd`; offloads done by the NIC are configured with ethtool.
+#[cfg(target_os = "linux")]
+pub fn disable_udp_offloads(socket: &UdpSocket, gso: bool, gro: bool) {
+    use std::os::unix::io::AsRawFd;
+
+    for (name, option, requested) in [("gso", libc::UDP_SEGMENT, gso), ("gro", libc::UDP_GRO, gro)]
+    {
+        if !requested {
+            continue;
+        }
+        match getsockopt_int(socket.as_raw_fd(), libc::SOL_UDP, option) {
+            Ok(0) => {
+                eprintln!(
+                    "udp {} is already off on the socket, NIC offloads are configured with ethtool",
+                    name
+                );
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("could not query udp {}, leaving it untouched: {}", name, e);
+                continue;
+            }
+        }
+        match setsockopt_int(socket.as_raw_fd(), libc::SOL_UDP, option, 0) {
+            Ok(()) => eprintln!("disabled udp {} on the socket", name),
+            Err(e) => eprintln!(
+                "could not disable udp {}, leaving it untouched: {}",
+                name, e
+            ),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn disable_udp_offloads(_socket: &UdpSocket, gso: bool, gro: bool) {
+    if gso || gro {
+        eprintln!("--no-gso/--no-gro are only supported on Linux, leaving offloads untouched");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_range_parses_inclusive_bounds() {
+        let range: PortRange = "40000:40100".parse().unwrap();
+        assert_eq!((range.start, range.end), (40000, 40100));
+        let range: PortRange = "1:65535".parse().unwrap();
+        assert_eq!((range.start, range.end), (1, 65535));
+        let single: PortRange = "5000:5000".parse().unwrap();
+        assert_eq!((single.start, single.end), (5000, 5000));
+    }
+
+    #[test]
+    fn port_range_rejects_malformed_and_empty_ranges() {
+        for spec in [
+            "", "5000", "5000:", ":5000", "0:10", "10:9", "1:65536", "a:b", "1-10",
+        ] {
+            assert!(spec.parse::<PortRange>().is_err(), "{:?} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn bind_failures_are_returned_with_the_address() {
+        // TEST-NET-1 is never assigned to a local interface.
+        let bind: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let remote: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let options = ConnectOptions {
+            bind: Some(bind),
+            ..ConnectOptions::default()
+        };
+        let e = options.connect_tcp(remote).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(e.to_string().starts_with("failed to bind 192.0.2.1:0: "));
+        let e = connect_udp(Some(bind), remote).unwrap_err();
+        assert!(e.to_string().starts_with("failed to bind 192.0.2.1:0: "));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recv_datagram_reports_the_length_of_an_oversized_reply() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = connect_udp(None, server.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        client.send(&[1; 8]).unwrap();
+        let mut request = [0; 64];
+        let (size, client_addr) = server.recv_from(&mut request).unwrap();
+        // Echo the request with two extra bytes appended.
+        let mut reply = request[..size].to_vec();
+        reply.extend_from_slice(&[2; 2]);
+        server.send_to(&reply, client_addr).unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(recv_datagram(&client, &mut buf).unwrap(), 10);
+        assert_eq!(buf, [1; 8]);
+    }
+}
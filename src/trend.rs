@@ -0,0 +1,195 @@
+//! The `trend` subcommand: how a statistic of the runs recorded with
+//! `--append-summary` moved over time.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+const STATISTICS: [&str; 8] = ["min", "max", "mean", "stddev", "p50", "p90", "p99", "p99.9"];
+
+/// One of the summary statistics, in microseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Statistic(&'static str);
+
+impl Statistic {
+    /// The key of the statistic in the json summary.
+    fn key(self) -> String {
+        format!("{}_us", self.0.replace('.', "_"))
+    }
+}
+
+impl FromStr for Statistic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        STATISTICS
+            .iter()
+            .find(|&&name| name == s)
+            .map(|&name| Statistic(name))
+            .ok_or_else(|| {
+                format!(
+                    "unknown statistic {:?}, expected one of {}",
+                    s,
+                    STATISTICS.join(", ")
+                )
+            })
+    }
+}
+
+/// Returns the raw value of the first `key` in a json `line`. The records are
+/// flat apart from the summary, whose keys are unique, and no value written
+/// by `--append-summary` holds a comma, so this needs no json parser.
+fn json_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("\"{}\":", key);
+    let rest = &line[line.find(&pattern)? + pattern.len()..];
+    let end = rest.find([',', '}']).unwrap_or(rest.len());
+    Some(rest[..end].trim())
+}
+
+/// A run appended with `--append-summary`.
+#[derive(Debug, PartialEq)]
+struct Record {
+    timestamp: String,
+    unix_time: u64,
+    target: Option<String>,
+    value: f64,
+}
+
+impl Record {
+    fn parse(line: &str, statistic: Statistic) -> Option<Self> {
+        let string = |raw: &str| {
+            raw.strip_prefix('"')
+                .and_then(|raw| raw.strip_suffix('"'))
+                .map(str::to_string)
+        };
+        Some(Record {
+            timestamp: string(json_field(line, "timestamp")?)?,
+            unix_time: json_field(line, "unix_time")?.parse().ok()?,
+            target: string(json_field(line, "target")?),
+            value: json_field(line, &statistic.key())?.parse().ok()?,
+        })
+    }
+}
+
+/// The least-squares slope of the records' values over time, in microseconds
+/// per day, or `None` if they were all appended within the same second.
+fn slope_per_day(records: &[Record]) -> Option<f64> {
+    let n = records.len() as f64;
+    let first = records.iter().map(|record| record.unix_time).min()?;
+    let points: Vec<(f64, f64)> = records
+        .iter()
+        .map(|record| ((record.unix_time - first) as f64 / 86400.0, record.value))
+        .collect();
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|&(x, _)| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|&(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    (sxx > 0.0).then(|| sxy / sxx)
+}
+
+/// Prints `statistic` of each run recorded in `path`, optionally only those
+/// against `target`, followed by how it changed from the first to the last.
+pub fn print_trend(path: &Path, statistic: Statistic, target: Option<&str>) -> io::Result<()> {
+    let mut records = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Record::parse(line, statistic) {
+            Some(record) if target.is_none() || record.target.as_deref() == target => {
+                records.push(record)
+            }
+            Some(_) => {}
+            None => eprintln!("skipping line {}: not a summary record", i + 1),
+        }
+    }
+    // Runs from several machines may be appended out of order.
+    records.sort_by_key(|record| record.unix_time);
+
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            eprintln!("no runs recorded in {}", path.display());
+            return Ok(());
+        }
+    };
+    for record in &records {
+        println!("{}  {:>10.1} us", record.timestamp, record.value);
+    }
+    let min = records
+        .iter()
+        .map(|r| r.value)
+        .fold(f64::INFINITY, f64::min);
+    let max = records
+        .iter()
+        .map(|r| r.value)
+        .fold(f64::NEG_INFINITY, f64::max);
+    println!("runs:   {}", records.len());
+    println!("first:  {:.1} us", first.value);
+    println!(
+        "last:   {:.1} us ({:+.1}%)",
+        last.value,
+        (last.value / first.value - 1.0) * 100.0
+    );
+    println!("min:    {:.1} us", min);
+    println!("max:    {:.1} us", max);
+    if let Some(slope) = slope_per_day(&records) {
+        println!("trend:  {:+.2} us/day", slope);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = "{\"timestamp\":\"2026-10-15T07:27:10Z\",\"unix_time\":1792049230,\"data_size\":1024,\"repeat\":1000,\"duration_secs\":null,\"warmup\":0,\"target\":\"127.0.0.1:8080\",\"summary\":{\"count\":1000,\"kept\":1000,\"min_us\":10.000,\"max_us\":90.000,\"mean_us\":20.000,\"stddev_us\":5.000,\"p50_us\":19.000,\"p90_us\":30.000,\"p99_us\":50.000,\"p99_9_us\":80.000}}";
+
+    #[test]
+    fn records_are_read_back_for_each_statistic() {
+        let record = Record::parse(LINE, "p99".parse().unwrap()).unwrap();
+        assert_eq!(
+            record,
+            Record {
+                timestamp: "2026-10-15T07:27:10Z".to_string(),
+                unix_time: 1792049230,
+                target: Some("127.0.0.1:8080".to_string()),
+                value: 50.0,
+            }
+        );
+        let p99_9 = Record::parse(LINE, "p99.9".parse().unwrap()).unwrap();
+        assert_eq!(p99_9.value, 80.0);
+        let min = Record::parse(LINE, "min".parse().unwrap()).unwrap();
+        assert_eq!(min.value, 10.0);
+    }
+
+    #[test]
+    fn unknown_statistics_and_other_lines_are_rejected() {
+        assert!("p95".parse::<Statistic>().is_err());
+        assert!(Record::parse("{\"samples_us\":[]}", "p99".parse().unwrap()).is_none());
+        let no_summary = LINE.replace("\"p50_us\"", "\"p55_us\"");
+        assert!(Record::parse(&no_summary, "p50".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn slope_is_in_microseconds_per_day() {
+        let record = |unix_time, value| Record {
+            timestamp: String::new(),
+            unix_time,
+            target: None,
+            value,
+        };
+        let records = [
+            record(0, 10.0),
+            record(86400, 12.0),
+            record(2 * 86400, 14.0),
+        ];
+        assert!((slope_per_day(&records).unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(slope_per_day(&[record(5, 10.0), record(5, 20.0)]), None);
+        assert_eq!(slope_per_day(&[]), None);
+    }
+}
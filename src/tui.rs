@@ -0,0 +1,169 @@
+//! Live terminal dashboard for the client round-trip loops.
+
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Stdout};
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::{Hide, Show};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::widgets::{BarChart, Block, Borders, Paragraph, Sparkline};
+use ratatui::Terminal;
+
+use crate::report::histogram_buckets;
+use crate::{percentile, PacketStats};
+
+/// Minimum time between two redraws.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+/// Number of the latest round trips kept for the sparkline.
+const RECENT_LEN: usize = 1024;
+
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    last_draw: Option<Instant>,
+    /// The latest round trips, which the kept samples may no longer hold
+    /// once a `--duration` run samples them.
+    recent: VecDeque<f64>,
+    /// The loss and reordering counters of a udp run.
+    packets: Option<PacketStats>,
+}
+
+impl Dashboard {
+    /// Switches stdout to the alternate screen, or returns `None` if stdout
+    /// is not a terminal so the caller can fall back to plain output.
+    pub fn new() -> Option<Self> {
+        let mut stdout = std::io::stdout();
+        if !stdout.is_terminal() {
+            return None;
+        }
+        execute!(stdout, EnterAlternateScreen, Hide).unwrap();
+        let terminal = Terminal::new(CrosstermBackend::new(stdout)).unwrap();
+        Some(Dashboard {
+            terminal,
+            last_draw: None,
+            recent: VecDeque::with_capacity(RECENT_LEN),
+            packets: None,
+        })
+    }
+
+    /// Adds the round trip of `last` microseconds, the `count`th so far, and
+    /// redraws the dashboard from the kept `samples` (in microseconds).
+    pub fn update(&mut self, samples: &[f64], count: usize, last: f64) {
+        if self.recent.len() == RECENT_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(last);
+        self.draw(samples, count);
+    }
+
+    /// Updates the udp `packets` counters, redrawing if a datagram was lost
+    /// since the last update, as no round trip gets recorded for it.
+    pub fn update_packets(&mut self, packets: PacketStats, samples: &[f64], count: usize) {
+        let lost = self
+            .packets
+            .replace(packets)
+            .map_or(packets.lost > 0, |previous| packets.lost > previous.lost);
+        if lost {
+            self.draw(samples, count);
+        }
+    }
+
+    /// Redraws the dashboard, at most once per refresh interval.
+    fn draw(&mut self, samples: &[f64], count: usize) {
+        if self
+            .last_draw
+            .is_some_and(|last_draw| last_draw.elapsed() < REFRESH_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let mut summary = match self.recent.back() {
+            Some(last) if !samples.is_empty() => format!(
+                "round trips: {}   last: {:.0} us   p50: {:.0} us   p90: {:.0} us   p99: {:.0} us   max: {:.0} us",
+                count,
+                last,
+                percentile(samples, 0.5),
+                percentile(samples, 0.9),
+                percentile(samples, 0.99),
+                percentile(samples, 1.0)
+            ),
+            _ => format!("round trips: {}", count),
+        };
+        if let Some(packets) = &self.packets {
+            summary.push_str(&format!(
+                "\npackets: {} sent   {} lost ({:.2}%)   {} late   {} out of order",
+                packets.sent,
+                packets.lost,
+                packets.lost as f64 * 100.0 / packets.sent.max(1) as f64,
+                packets.late,
+                packets.out_of_order
+            ));
+        }
+        let summary_height = summary.lines().count() as u16 + 2;
+
+        let micros: Vec<u64> = samples.iter().map(|&sample| sample as u64).collect();
+        let buckets = histogram_buckets(&micros);
+        let labels: Vec<String> = buckets.iter().map(|(lower, _)| lower.to_string()).collect();
+        let bars: Vec<(&str, u64)> = labels
+            .iter()
+            .map(String::as_str)
+            .zip(buckets.iter().map(|&(_, count)| count as u64))
+            .collect();
+
+        self.terminal
+            .draw(|frame| {
+                let [summary_area, sparkline_area, histogram_area] = Layout::vertical([
+                    Constraint::Length(summary_height),
+                    Constraint::Length(8),
+                    Constraint::Min(8),
+                ])
+                .areas(frame.area());
+
+                let recent: Vec<u64> = self
+                    .recent
+                    .iter()
+                    .rev()
+                    .take(sparkline_area.width.saturating_sub(2) as usize)
+                    .rev()
+                    .map(|&sample| sample as u64)
+                    .collect();
+
+                frame.render_widget(
+                    Paragraph::new(summary)
+                        .block(Block::default().borders(Borders::ALL).title("latency")),
+                    summary_area,
+                );
+                frame.render_widget(
+                    Sparkline::default()
+                        .block(
+                            Block::default()
+                                .borders(Borders::ALL)
+                                .title("recent round trips"),
+                        )
+                        .data(recent),
+                    sparkline_area,
+                );
+                frame.render_widget(
+                    BarChart::default()
+                        .block(
+                            Block::default()
+                                .borders(Borders::ALL)
+                                .title("histogram (us)"),
+                        )
+                        .bar_width(6)
+                        .data(&bars),
+                    histogram_area,
+                );
+            })
+            .unwrap();
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        let _ = execute!(self.terminal.backend_mut(), Show, LeaveAlternateScreen);
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Instant;
+
+use rand::RngCore;
+
+use crate::{run_client_loop, ClientError, ClientOpts, Echo};
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A `ws://host[:port]/path` url.
+#[derive(Debug)]
+pub struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = if let Some(rest) = url.strip_prefix("ws://") {
+            rest
+        } else if url.starts_with("wss://") {
+            return Err("wss:// is not supported: this build has no TLS support".to_string());
+        } else {
+            return Err(format!(
+                "unsupported websocket url {:?}, expected ws://host[:port]/path",
+                url
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|e| format!("invalid port in websocket url: {}", e))?,
+            ),
+            None => (authority, 80),
+        };
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+fn handshake(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    url: &Url,
+) -> io::Result<()> {
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut key);
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}:{}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        url.path,
+        url.host,
+        url.port,
+        base64::encode(key)
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(io::Error::other(format!(
+            "websocket upgrade rejected: {}",
+            status.trim_end()
+        )));
+    }
+    // Skip the remaining response headers up to the blank line.
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during websocket handshake",
+            ));
+        }
+        if line == "\r\n" {
+            return Ok(());
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    // Client-to-server frames must always be masked.
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mut mask = [0u8; 4];
+    rand::thread_rng().fill_bytes(&mut mask);
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+fn read_frame(reader: &mut BufReader<TcpStream>) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            u16::from_be_bytes(buf) as usize
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf) as usize
+        }
+        len => len as usize,
+    };
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// Reads the next data message, answering pings and reassembling fragments.
+fn read_message(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+    let mut message = Vec::new();
+    loop {
+        let (fin, opcode, payload) = read_frame(reader)?;
+        match opcode {
+            OPCODE_PING => write_frame(stream, OPCODE_PONG, &payload)?,
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "websocket closed by server",
+                ))
+            }
+            _ => {
+                message.extend_from_slice(&payload);
+                if fin {
+                    return Ok(message);
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `url` and completes the websocket upgrade.
+fn connect(url: &Url) -> io::Result<(TcpStream, BufReader<TcpStream>)> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("websocket host did not resolve to any address"))?;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    handshake(&mut stream, &mut reader, url)?;
+    Ok((stream, reader))
+}
+
+pub fn start_websocket_client(url: Url, client_opts: ClientOpts) -> Result<(), ClientError> {
+    let target = format!("{}:{}", url.host, url.port);
+    let start = Instant::now();
+    let (mut stream, mut reader) = connect(&url).map_err(|source| ClientError::Connect {
+        target: target.clone(),
+        source,
+    })?;
+    // The handshake is a one-off, keep it out of the round trip samples.
+    eprintln!("{} us elapsed (handshake)", start.elapsed().as_micros());
+
+    let client_run = run_client_loop(&client_opts, Some(target), None, None, |_, data| {
+        let start = Instant::now();
+        write_frame(&mut stream, OPCODE_BINARY, data)?;
+        let reply = read_message(&mut stream, &mut reader)?;
+        Ok(Some((start.elapsed(), Echo::compare(data, &reply))))
+    });
+
+    // The server may already be gone, the close is only a courtesy.
+    let _ = write_frame(&mut stream, OPCODE_CLOSE, &[]);
+    let _ = stream.shutdown(Shutdown::Both);
+    client_run.result
+}